@@ -0,0 +1,89 @@
+//! Measures handing a sustained stream of received video to the user through the video channel handler, the time and the allocations for each chunk.
+//! The video is acknowledged to a stream that discards it and delivered to the mock head unit.
+//! Run it with `cargo bench --features test-util --bench video_delivery`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// An allocator that counts the allocations made and the bytes they asked for
+struct CountingAllocator;
+
+/// The number of allocations made so far, reallocations included
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// The number of bytes asked for by the allocations made so far
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// The number of chunks of video in the stream
+const CHUNKS: usize = 10_000;
+
+/// The size of each chunk of video, a large frame as phones send them
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The decrypted payload of a media indication with a timestamp, as it comes out of decryption
+fn payload() -> Vec<u8> {
+    let mut p = vec![0x55u8; CHUNK_SIZE + 10];
+    p[..2].copy_from_slice(&0u16.to_be_bytes());
+    p
+}
+
+/// Hand every chunk of the stream over with the specified function and print the cost of each one
+fn measure<T>(name: &str, mut hand_over: impl FnMut(Vec<u8>) -> T) {
+    let mut elapsed = Duration::ZERO;
+    let mut allocations = 0;
+    let mut allocated = 0;
+    for _ in 0..CHUNKS {
+        let p = payload();
+        let before = (
+            ALLOCATIONS.load(Ordering::Relaxed),
+            ALLOCATED.load(Ordering::Relaxed),
+        );
+        let start = Instant::now();
+        std::hint::black_box(hand_over(std::hint::black_box(p)));
+        elapsed += start.elapsed();
+        allocations += ALLOCATIONS.load(Ordering::Relaxed) - before.0;
+        allocated += ALLOCATED.load(Ordering::Relaxed) - before.1;
+    }
+    println!(
+        "{name}: {:?} per chunk, {:.2} allocations and {} bytes allocated per chunk",
+        elapsed / CHUNKS as u32,
+        allocations as f64 / CHUNKS as f64,
+        allocated / CHUNKS
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let bench = runtime.block_on(android_auto::VideoDeliveryBench::new());
+    measure("video channel handler", |p| {
+        runtime.block_on(bench.receive(p))
+    });
+    measure("draining the header of the vec", |mut p| {
+        p.drain(..10);
+        p
+    });
+    measure("copying into a new vec", |p| p[10..].to_vec());
+}
@@ -74,7 +74,7 @@ struct AndroidAuto {
 
 enum MessageFromAsync {
     VideoData {
-        data: Vec<u8>,
+        data: android_auto::bytes::Bytes,
         _timestamp: Option<u64>,
     },
     Connected,
@@ -88,7 +88,7 @@ enum MessageToAsync {
 
 #[async_trait::async_trait]
 impl android_auto::AndroidAutoVideoChannelTrait for AndroidAuto {
-    async fn receive_video(&self, data: Vec<u8>, timestamp: Option<u64>) {
+    async fn receive_video(&self, data: android_auto::bytes::Bytes, timestamp: Option<u64>) {
         let i = self.inner.lock().await;
         let _ = i
             .send
@@ -99,7 +99,10 @@ impl android_auto::AndroidAutoVideoChannelTrait for AndroidAuto {
             .await;
     }
 
-    async fn setup_video(&self) -> Result<(), ()> {
+    async fn setup_video(
+        &self,
+        _config: &VideoConfiguration,
+    ) -> Result<(), android_auto::ChannelOpenError> {
         Ok(())
     }
 
@@ -130,7 +133,11 @@ impl android_auto::AndroidAutoSensorTrait for AndroidAuto {
         &self.sensors
     }
 
-    async fn start_sensor(&self, stype: android_auto::Wifi::sensor_type::Enum) -> Result<(), ()> {
+    async fn start_sensor(
+        &self,
+        request: &android_auto::Wifi::SensorStartRequestMessage,
+    ) -> Result<(), ()> {
+        let stype = request.sensor_type();
         if self.sensors.sensors.contains(&stype) {
             let mut m3 = android_auto::Wifi::SensorEventIndication::new();
             match stype {
@@ -160,7 +167,10 @@ impl android_auto::AndroidAutoSensorTrait for AndroidAuto {
 
 #[async_trait::async_trait]
 impl android_auto::AndroidAutoAudioOutputTrait for AndroidAuto {
-    async fn open_output_channel(&self, _t: android_auto::AudioChannelType) -> Result<(), ()> {
+    async fn open_output_channel(
+        &self,
+        _t: android_auto::AudioChannelType,
+    ) -> Result<(), android_auto::ChannelOpenError> {
         Ok(())
     }
 
@@ -232,7 +242,7 @@ impl android_auto::AndroidAutoInputChannelTrait for AndroidAuto {
 
 #[async_trait::async_trait]
 impl android_auto::AndroidAutoAudioInputTrait for AndroidAuto {
-    async fn open_input_channel(&self) -> Result<(), ()> {
+    async fn open_input_channel(&self) -> Result<(), android_auto::ChannelOpenError> {
         log::error!("Start audio input channel");
         let mut s = self.inner.lock().await;
         let config = cpal::StreamConfig {
@@ -515,8 +525,16 @@ impl AndroidAuto {
                 resolution: android_auto::Wifi::video_resolution::Enum::_480p,
                 fps: android_auto::Wifi::video_fps::Enum::_30,
                 dpi: 111,
+                margin_width: 0,
+                margin_height: 0,
+                frame_drop: Default::default(),
+                packetization: Default::default(),
+                orientation: Default::default(),
+            },
+            sensors: android_auto::SensorInformation {
+                sensors: s,
+                min_intervals: std::collections::HashMap::new(),
             },
-            sensors: android_auto::SensorInformation { sensors: s },
             input_config: android_auto::InputConfiguration {
                 keycodes: vec![1, 2, 3, 4, 5],
                 touchscreen: Some((800, 480)),
@@ -833,6 +851,7 @@ impl AndroidAutoContainer {
                         port: 5277,
                         security_mode: android_auto::Bluetooth::SecurityMode::WPA2_PERSONAL,
                         ap_type: android_auto::Bluetooth::AccessPointType::STATIC,
+                        keepalive: Some(Default::default()),
                     },
                     aauto.1,
                     aauto.0,
@@ -852,6 +871,15 @@ impl AndroidAutoContainer {
                         hide_clock: Some(true),
                     },
                     custom_certificate: None,
+                    extra_roots: Vec::new(),
+                    recorder: None,
+                    idle_timeout: None,
+                    handshake_timeout: None,
+                    bluetooth_profile: Default::default(),
+                    clock: None,
+                    overflow: Default::default(),
+                    ping_only_when_idle: false,
+                    max_frame_data_size: None,
                 };
                 tokio::select! {
                     _ = aa.start_android_auto(config, setup) => {
@@ -0,0 +1,11 @@
+//! Fuzz the frame parser with arbitrary input, it must never panic
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(f) = android_auto::parse_frame(data) {
+        let _ = (f.channel_id(), f.is_control(), f.is_encrypted(), f.data());
+    }
+});
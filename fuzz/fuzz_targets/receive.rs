@@ -0,0 +1,11 @@
+//! Fuzz the frame receiver and the message parsers of every channel with arbitrary unencrypted input, they must never panic
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for f in android_auto::receive_unencrypted(data) {
+        let _ = (f.channel_id(), f.is_control(), f.data());
+    }
+});
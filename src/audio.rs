@@ -0,0 +1,367 @@
+//! This is for the audio output channel handler code, shared by the media, speech and system audio channels
+
+use protobuf::Message;
+
+use crate::{
+    AndroidAutoConfiguration, AndroidAutoFrame, AndroidAutoMainTrait, AudioChannelType,
+    AudioFormat, AvChannelMessage, ChannelHandlerTrait, ChannelId, ChannelKind, StreamMux, Wifi,
+    common::AndroidAutoCommonMessage,
+};
+
+/// The handler for an audio output channel for the android auto protocol.
+/// Audio on these channels only flows from the compatible android auto device to the head unit, the protocol has no way to send audio the other way on them.
+/// Audio from the head unit goes over the audio input channel instead.
+pub struct AudioChannelHandler {
+    /// The audio channel this handles
+    kind: AudioChannelType,
+    /// The result of opening the channel with the user, set by whichever of the channel open request or setup request arrives first
+    opened: tokio::sync::OnceCell<bool>,
+    /// The formats advertised for the channel, in order
+    formats: std::sync::Mutex<Vec<AudioFormat>>,
+}
+
+impl AudioChannelHandler {
+    /// Construct a new self for the specified audio channel
+    pub fn new(kind: AudioChannelType) -> Self {
+        Self {
+            kind,
+            opened: tokio::sync::OnceCell::new(),
+            formats: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// The kind of channel for this audio channel
+    fn channel_kind(&self) -> ChannelKind {
+        match self.kind {
+            AudioChannelType::Media => ChannelKind::MediaAudio,
+            AudioChannelType::Speech => ChannelKind::SpeechAudio,
+            AudioChannelType::System => ChannelKind::SystemAudio,
+        }
+    }
+
+    /// The audio type advertised for this audio channel
+    fn audio_type(&self) -> Wifi::audio_type::Enum {
+        match self.kind {
+            AudioChannelType::Media => Wifi::audio_type::Enum::MEDIA,
+            AudioChannelType::Speech => Wifi::audio_type::Enum::SPEECH,
+            AudioChannelType::System => Wifi::audio_type::Enum::SYSTEM,
+        }
+    }
+
+    /// Open the channel with the user, only the first call does so unless it fails transiently
+    async fn open<T: AndroidAutoMainTrait + ?Sized>(&self, main: &T) -> bool {
+        super::open_channel_once(
+            &self.opened,
+            self.channel_kind(),
+            main.open_output_channel(self.kind),
+        )
+        .await
+    }
+}
+
+impl ChannelHandlerTrait for AudioChannelHandler {
+    fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        _config: &AndroidAutoConfiguration,
+        chanid: ChannelId,
+        main: &T,
+    ) -> Option<Wifi::ChannelDescriptor> {
+        let mut formats = main.output_audio_formats(self.kind);
+        if formats.is_empty() {
+            log::error!(
+                "No audio formats for the {:?} channel, advertising the default",
+                self.kind
+            );
+            formats.push(self.kind.default_format());
+        }
+        let mut chan = Wifi::ChannelDescriptor::new();
+        chan.set_channel_id(chanid as u32);
+        let mut avchan = Wifi::AVChannel::new();
+        avchan.set_audio_type(self.audio_type());
+        avchan.set_available_while_in_call(true);
+        avchan.set_stream_type(Wifi::avstream_type::Enum::AUDIO);
+        for f in &formats {
+            let mut ac = Wifi::AudioConfig::new();
+            ac.set_bit_depth(f.bit_depth);
+            ac.set_channel_count(f.channel_count);
+            ac.set_sample_rate(f.sample_rate);
+            avchan.audio_configs.push(ac);
+        }
+        *self.formats.lock().unwrap() = formats;
+        chan.av_channel.0.replace(Box::new(avchan));
+        if !chan.is_initialized() {
+            panic!("Channel not initialized?");
+        }
+        Some(chan)
+    }
+
+    async fn receive_data<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        msg: AndroidAutoFrame,
+        stream: &crate::WriteHalf,
+        config: &AndroidAutoConfiguration,
+        main: &T,
+    ) -> Result<(), super::FrameIoError> {
+        let channel = msg.header.channel_id;
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
+            match msg2 {
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        self.channel_kind(),
+                        channel,
+                        &m,
+                        self.open(main),
+                        stream,
+                    )
+                    .await?;
+                }
+            }
+            return Ok(());
+        }
+        if let Some(msg2) = msg.parse::<AvChannelMessage>(&mut errors) {
+            match msg2 {
+                AvChannelMessage::AvChannelOpen(_chan, _m) => msg.log_unhandled(),
+                AvChannelMessage::AvChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AvChannelMessage::MediaIndicationAck(_, _) => msg.log_unhandled(),
+                AvChannelMessage::MediaIndication(_chan, timestamp, data) => {
+                    if let Some(r) = &config.recorder {
+                        r.record_audio(self.kind, &data, timestamp);
+                    }
+                    main.receive_output_audio(self.kind, data).await
+                }
+                AvChannelMessage::SetupRequest(_chan, m) => {
+                    let index = m.config_index();
+                    let format = self.formats.lock().unwrap().get(index as usize).copied();
+                    let opened = match format {
+                        Some(format) => {
+                            main.output_audio_format(self.kind, format).await;
+                            self.open(main).await
+                        }
+                        None => {
+                            log::error!(
+                                "{:?} audio setup chose configuration {}, which was not advertised",
+                                self.kind,
+                                index
+                            );
+                            false
+                        }
+                    };
+                    let mut m2 = Wifi::AVChannelSetupResponse::new();
+                    m2.set_max_unacked(10);
+                    m2.set_media_status(if opened {
+                        Wifi::avchannel_setup_status::Enum::OK
+                    } else {
+                        Wifi::avchannel_setup_status::Enum::FAIL
+                    });
+                    if format.is_some() {
+                        m2.configs.push(index);
+                    }
+                    stream
+                        .write_frame(AvChannelMessage::SetupResponse(channel, m2).into())
+                        .await?;
+                    if opened {
+                        stream.state().channel_opened(channel, self.channel_kind());
+                    }
+                }
+                AvChannelMessage::SetupResponse(_chan, _m) => msg.log_unhandled(),
+                AvChannelMessage::VideoFocusRequest(_chan, _m) => {
+                    let mut m2 = Wifi::VideoFocusIndication::new();
+                    m2.set_focus_mode(Wifi::video_focus_mode::Enum::FOCUSED);
+                    m2.set_unrequested(false);
+                    stream
+                        .write_frame(AvChannelMessage::VideoIndicationResponse(channel, m2).into())
+                        .await?;
+                }
+                AvChannelMessage::VideoIndicationResponse(_, _) => msg.log_unhandled(),
+                AvChannelMessage::StartIndication(_, _) => {
+                    main.start_output_audio(self.kind).await;
+                }
+                AvChannelMessage::StopIndication(_, _) => {
+                    main.stop_output_audio(self.kind).await;
+                }
+            }
+            return Ok(());
+        }
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+
+    async fn connection_ended<T: AndroidAutoMainTrait + ?Sized>(&self, main: &T) {
+        if self.opened.get() == Some(&true) && main.close_output_channel(self.kind).await.is_err() {
+            log::error!("The {:?} audio channel could not be closed", self.kind);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCall, MockMain};
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn setup_before_channel_open_opens_once() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = AudioChannelHandler::new(AudioChannelType::Media);
+        handler.build_channel(&config, 4, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(4, 0), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(
+            setup_status(&sent.frames()),
+            Wifi::avchannel_setup_status::Enum::OK
+        );
+        handler
+            .receive_data(channel_open_request(4), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(channel_open_status(&sent.frames()), Wifi::status::Enum::OK);
+        let format = AudioChannelType::Media.default_format();
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::OutputAudioFormat(AudioChannelType::Media, format),
+                MockCall::OpenOutputChannel(AudioChannelType::Media)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn setup_for_an_unadvertised_configuration_fails() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = AudioChannelHandler::new(AudioChannelType::Media);
+        handler.build_channel(&config, 4, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(4, 1), &stream, &config, &main)
+            .await
+            .unwrap();
+        let r: Wifi::AVChannelSetupResponse = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::SETUP_RESPONSE as u16,
+        );
+        assert_eq!(r.media_status(), Wifi::avchannel_setup_status::Enum::FAIL);
+        assert!(r.configs.is_empty());
+        assert!(!stream.state().is_channel_open(4));
+        assert!(main.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn setup_chooses_the_advertised_format() {
+        let mono = AudioFormat {
+            sample_rate: 16000,
+            channel_count: 1,
+            bit_depth: 16,
+        };
+        let main = MockMain::default().with_support(crate::mock::MockSupport {
+            extra_output_formats: vec![mono],
+            ..Default::default()
+        });
+        let config = MockMain::configuration();
+        let handler = AudioChannelHandler::new(AudioChannelType::Media);
+        handler.build_channel(&config, 4, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(4, 1), &stream, &config, &main)
+            .await
+            .unwrap();
+        let r: Wifi::AVChannelSetupResponse = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::SETUP_RESPONSE as u16,
+        );
+        assert_eq!(r.media_status(), Wifi::avchannel_setup_status::Enum::OK);
+        assert_eq!(r.configs, vec![1]);
+        assert!(stream.state().is_channel_open(4));
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::OutputAudioFormat(AudioChannelType::Media, mono),
+                MockCall::OpenOutputChannel(AudioChannelType::Media)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn transient_failure_is_asked_again() {
+        let main = MockMain::default().with_behavior(crate::mock::MockBehavior {
+            transient_open_failures: 1,
+            ..Default::default()
+        });
+        let config = MockMain::configuration();
+        let handler = AudioChannelHandler::new(AudioChannelType::Media);
+        handler.build_channel(&config, 4, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(4, 0), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(
+            setup_status(&sent.frames()),
+            Wifi::avchannel_setup_status::Enum::FAIL
+        );
+        handler
+            .receive_data(setup_request(4, 0), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(
+            setup_status(&sent.frames()),
+            Wifi::avchannel_setup_status::Enum::OK
+        );
+        let format = AudioChannelType::Media.default_format();
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::OutputAudioFormat(AudioChannelType::Media, format),
+                MockCall::OpenOutputChannel(AudioChannelType::Media),
+                MockCall::OutputAudioFormat(AudioChannelType::Media, format),
+                MockCall::OpenOutputChannel(AudioChannelType::Media)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn opened_channel_is_closed_when_the_connection_ends() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let media = AudioChannelHandler::new(AudioChannelType::Media);
+        let speech = AudioChannelHandler::new(AudioChannelType::Speech);
+        media.build_channel(&config, 4, &main);
+        speech.build_channel(&config, 5, &main);
+        let (stream, _sent) = crate::WriteHalf::capturing();
+        media
+            .receive_data(channel_open_request(4), &stream, &config, &main)
+            .await
+            .unwrap();
+        media.connection_ended(&main).await;
+        speech.connection_ended(&main).await;
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::OpenOutputChannel(AudioChannelType::Media),
+                MockCall::CloseOutputChannel(AudioChannelType::Media)
+            ]
+        );
+    }
+
+    #[test]
+    fn media_channel_advertises_stereo_48khz() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = AudioChannelHandler::new(AudioChannelType::Media);
+        let chan = handler.build_channel(&config, 4, &main).unwrap();
+        let av = chan.av_channel.as_ref().unwrap();
+        assert_eq!(av.audio_type(), Wifi::audio_type::Enum::MEDIA);
+        let configs: Vec<_> = av
+            .audio_configs
+            .iter()
+            .map(|c| (c.channel_count(), c.sample_rate(), c.bit_depth()))
+            .collect();
+        assert_eq!(configs, vec![(2, 48000, 16)]);
+    }
+}
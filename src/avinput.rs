@@ -4,12 +4,73 @@ use protobuf::Message;
 
 use crate::{
     AndroidAutoConfiguration, AndroidAutoFrame, AndroidAutoMainTrait, AvChannelMessage,
-    ChannelHandlerTrait, ChannelId, FrameIoError, StreamMux, Wifi,
+    ChannelHandlerTrait, ChannelId, ChannelOpenError, FrameIoError, StreamMux, Wifi,
     common::AndroidAutoCommonMessage,
 };
 
+/// The value of an input open response when opening or closing the microphone succeeded
+const OPEN_RESPONSE_OK: u32 = 0;
+/// The value of an input open response when opening or closing the microphone failed, any value other than [`OPEN_RESPONSE_OK`] is a failure
+const OPEN_RESPONSE_FAIL: u32 = 1;
+
 /// Handles the av input channel of the android auto protocol
-pub struct AvInputChannelHandler {}
+pub struct AvInputChannelHandler {
+    /// The configuration index selected by the setup request, None until the channel has been setup
+    config_index: std::sync::Mutex<Option<u32>>,
+    /// The session of the started input stream
+    session: std::sync::Mutex<Option<i32>>,
+}
+
+impl AvInputChannelHandler {
+    /// Construct a new self
+    pub fn new() -> Self {
+        Self {
+            config_index: std::sync::Mutex::new(None),
+            session: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// Validate a message being sent to the compatible android auto device on this channel.
+    /// A start indication is only allowed after setup, is given the configuration selected during setup,
+    /// and may not replace the session of a stream that is already started.
+    /// A stop indication is only allowed while a stream is started.
+    /// Returns true for start and stop indications, so that the session is updated and the user is told once they have been sent.
+    pub fn check_outgoing(&self, data: &mut Vec<u8>) -> Result<bool, String> {
+        if data.len() < 2 {
+            return Ok(false);
+        }
+        let ty = u16::from_be_bytes([data[0], data[1]]);
+        if ty == Wifi::avchannel_message::Enum::STOP_INDICATION as u16 {
+            if self.session.lock().unwrap().is_none() {
+                return Err("Audio input stop without a started stream".to_string());
+            }
+            return Ok(true);
+        }
+        if ty != Wifi::avchannel_message::Enum::START_INDICATION as u16 {
+            return Ok(false);
+        }
+        let mut m = Wifi::AVChannelStartIndication::parse_from_bytes(&data[2..])
+            .map_err(|e| format!("Invalid start indication: {}", e))?;
+        let config_index = self
+            .config_index
+            .lock()
+            .unwrap()
+            .ok_or("Audio input start before the channel was setup".to_string())?;
+        if let Some(s) = *self.session.lock().unwrap()
+            && s != m.session()
+        {
+            return Err(format!(
+                "Audio input start with session {} while session {} is active",
+                m.session(),
+                s
+            ));
+        }
+        m.set_config(config_index);
+        data.truncate(2);
+        data.append(&mut m.write_to_bytes().map_err(|e| e.to_string())?);
+        Ok(true)
+    }
+}
 
 impl ChannelHandlerTrait for AvInputChannelHandler {
     fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
@@ -43,62 +104,212 @@ impl ChannelHandlerTrait for AvInputChannelHandler {
         main: &T,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::AvInput,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        let msg2: Result<AvChannelMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        if let Some(msg2) = msg.parse::<AvChannelMessage>(&mut errors) {
             log::info!("Received: {channel} {:?}", msg2);
             match msg2 {
                 AvChannelMessage::AvChannelOpen(_chan, m) => {
-                    if m.open() {
-                        main.open_input_channel()
-                            .await
-                            .map_err(|_| FrameIoError::AudioInputOpenError)?;
+                    let ok = if m.open() {
+                        match main.open_input_channel().await {
+                            Ok(()) => true,
+                            Err(ChannelOpenError::Transient) => {
+                                log::warn!(
+                                    "Audio input is not available right now, the phone may ask again"
+                                );
+                                false
+                            }
+                            Err(ChannelOpenError::Permanent) => {
+                                log::error!("Audio input could not be opened");
+                                false
+                            }
+                        }
+                    } else if main.close_input_channel().await.is_err() {
+                        log::error!("Audio input could not be closed");
+                        false
                     } else {
-                        main.close_input_channel()
-                            .await
-                            .map_err(|_| FrameIoError::AudioInputCloseError)?;
-                    }
+                        true
+                    };
+                    let mut m2 = Wifi::AVInputOpenResponse::new();
+                    m2.set_session(self.session.lock().unwrap().unwrap_or(0));
+                    m2.set_value(if ok {
+                        OPEN_RESPONSE_OK
+                    } else {
+                        OPEN_RESPONSE_FAIL
+                    });
+                    stream
+                        .write_frame(AvChannelMessage::AvChannelOpenResponse(channel, m2).into())
+                        .await?;
                 }
+                AvChannelMessage::AvChannelOpenResponse(_, _) => msg.log_unhandled(),
                 AvChannelMessage::MediaIndicationAck(chan, ack) => {
+                    let session = *self.session.lock().unwrap();
+                    if let Some(s) = session
+                        && s != ack.session()
+                    {
+                        log::warn!(
+                            "Audio input ack for session {}, expected {}",
+                            ack.session(),
+                            s
+                        );
+                    }
                     main.audio_input_ack(chan, ack).await;
                 }
-                AvChannelMessage::MediaIndication(_chan, _timestamp, _data) => unimplemented!(),
-                AvChannelMessage::SetupRequest(_chan, _m) => {
+                AvChannelMessage::MediaIndication(_chan, _timestamp, _data) => msg.log_unhandled(),
+                AvChannelMessage::SetupRequest(_chan, m) => {
+                    let index = m.config_index();
+                    // only a single audio configuration is advertised for the microphone
+                    let valid = index == 0;
                     let mut m2 = Wifi::AVChannelSetupResponse::new();
                     m2.set_max_unacked(10);
-                    m2.set_media_status(Wifi::avchannel_setup_status::Enum::OK);
-                    m2.configs.push(0);
+                    m2.set_media_status(if valid {
+                        Wifi::avchannel_setup_status::Enum::OK
+                    } else {
+                        Wifi::avchannel_setup_status::Enum::FAIL
+                    });
+                    if valid {
+                        m2.configs.push(index);
+                    }
                     stream
                         .write_frame(AvChannelMessage::SetupResponse(channel, m2).into())
                         .await?;
+                    if !valid {
+                        log::error!(
+                            "Audio input setup chose configuration {}, which was not advertised",
+                            index
+                        );
+                        return Ok(());
+                    }
+                    self.config_index.lock().unwrap().replace(index);
+                    stream
+                        .state()
+                        .channel_opened(channel, crate::ChannelKind::AvInput);
                 }
-                AvChannelMessage::SetupResponse(_chan, _m) => unimplemented!(),
-                AvChannelMessage::VideoFocusRequest(_chan, _m) => unimplemented!(),
-                AvChannelMessage::VideoIndicationResponse(_, _) => unimplemented!(),
-                AvChannelMessage::StartIndication(_, _) => {
+                AvChannelMessage::SetupResponse(_chan, _m) => msg.log_unhandled(),
+                AvChannelMessage::VideoFocusRequest(_chan, _m) => msg.log_unhandled(),
+                AvChannelMessage::VideoIndicationResponse(_, _) => msg.log_unhandled(),
+                AvChannelMessage::StartIndication(_, m) => {
+                    self.session.lock().unwrap().replace(m.session());
                     main.start_input_audio().await;
                 }
                 AvChannelMessage::StopIndication(_, _) => {
+                    self.session.lock().unwrap().take();
                     main.stop_input_audio().await;
                 }
             }
             return Ok(());
         }
-        todo!("{:x?}", msg);
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+
+    async fn sent_data<T: AndroidAutoMainTrait + ?Sized>(&self, msg: &AndroidAutoFrame, main: &T) {
+        let msg2: Result<AvChannelMessage, String> = msg.try_into();
+        match msg2 {
+            Ok(AvChannelMessage::StartIndication(_, m)) => {
+                self.session.lock().unwrap().replace(m.session());
+                main.start_input_audio().await;
+            }
+            Ok(AvChannelMessage::StopIndication(_, _)) => {
+                self.session.lock().unwrap().take();
+                main.stop_input_audio().await;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCall, MockMain};
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn failed_open_keeps_the_connection() {
+        let main = MockMain::default().with_behavior(crate::mock::MockBehavior {
+            open_channel: Err(ChannelOpenError::Permanent),
+            ..Default::default()
+        });
+        let config = MockMain::configuration();
+        let handler = AvInputChannelHandler::new();
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        let mut m = Wifi::AVInputOpenRequest::new();
+        m.set_open(true);
+        let open = incoming(
+            4,
+            false,
+            Wifi::avchannel_message::Enum::AV_INPUT_OPEN_REQUEST as u16,
+            &m,
+        );
+        handler
+            .receive_data(open, &stream, &config, &main)
+            .await
+            .unwrap();
+        let r: Wifi::AVInputOpenResponse = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::AV_INPUT_OPEN_RESPONSE as u16,
+        );
+        assert_eq!(r.value(), OPEN_RESPONSE_FAIL);
+        assert_eq!(main.calls(), vec![MockCall::OpenInputChannel]);
+    }
+
+    #[tokio::test]
+    async fn session_starts_once_the_start_indication_is_sent() {
+        let main = MockMain::default();
+        let handler = AvInputChannelHandler::new();
+        handler.config_index.lock().unwrap().replace(0);
+        let start = |session| {
+            let mut m = Wifi::AVChannelStartIndication::new();
+            m.set_session(session);
+            m.set_config(0);
+            incoming(
+                6,
+                false,
+                Wifi::avchannel_message::Enum::START_INDICATION as u16,
+                &m,
+            )
+        };
+        // a start indication that was never sent does not start a session
+        assert!(handler.check_outgoing(&mut start(1).data).unwrap());
+        assert!(handler.check_outgoing(&mut start(2).data).unwrap());
+        handler.sent_data(&start(2), &main).await;
+        assert_eq!(*handler.session.lock().unwrap(), Some(2));
+        assert!(handler.check_outgoing(&mut start(1).data).is_err());
+        assert_eq!(main.calls(), vec![MockCall::StartInputAudio]);
+    }
+
+    #[tokio::test]
+    async fn setup_for_an_unadvertised_configuration_fails() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = AvInputChannelHandler::new();
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(6, 1), &stream, &config, &main)
+            .await
+            .unwrap();
+        let r: Wifi::AVChannelSetupResponse = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::SETUP_RESPONSE as u16,
+        );
+        assert_eq!(r.media_status(), Wifi::avchannel_setup_status::Enum::FAIL);
+        assert!(r.configs.is_empty());
+        assert!(!stream.state().is_channel_open(6));
     }
 }
@@ -2,7 +2,7 @@
 
 use super::{
     AndroidAutoCommonMessage, AndroidAutoConfiguration, AndroidAutoFrame, ChannelDescriptor,
-    ChannelHandlerTrait, ChannelId, FrameHeader, FrameHeaderContents, FrameHeaderType,
+    ChannelHandlerTrait, ChannelId, FrameHeader, FrameHeaderContents,
 };
 use crate::{AndroidAutoMainTrait, StreamMux, Wifi};
 use protobuf::{EnumOrUnknown, Message};
@@ -31,7 +31,7 @@ impl From<BluetoothMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -44,9 +44,7 @@ impl TryFrom<&AndroidAutoFrame> for BluetoothMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
         use protobuf::Enum;
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if let Some(sys) = Wifi::bluetooth_channel_message::Enum::from_i32(ty as i32) {
             match sys {
                 Wifi::bluetooth_channel_message::Enum::PAIRING_REQUEST => {
@@ -56,9 +54,15 @@ impl TryFrom<&AndroidAutoFrame> for BluetoothMessage {
                         Err(e) => Err(e.to_string()),
                     }
                 }
-                Wifi::bluetooth_channel_message::Enum::PAIRING_RESPONSE => unimplemented!(),
-                Wifi::bluetooth_channel_message::Enum::AUTH_DATA => todo!(),
-                Wifi::bluetooth_channel_message::Enum::NONE => unimplemented!(),
+                Wifi::bluetooth_channel_message::Enum::PAIRING_RESPONSE => {
+                    Err("Unsupported message PAIRING_RESPONSE".to_string())
+                }
+                Wifi::bluetooth_channel_message::Enum::AUTH_DATA => {
+                    Err("Unsupported message AUTH_DATA".to_string())
+                }
+                Wifi::bluetooth_channel_message::Enum::NONE => {
+                    Err("Unsupported message NONE".to_string())
+                }
             }
         } else {
             Err(format!("Not converted message: {:x?}", value.data))
@@ -102,10 +106,10 @@ impl ChannelHandlerTrait for BluetoothChannelHandler {
         _main: &T,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<BluetoothMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<BluetoothMessage>(&mut errors) {
             match msg2 {
-                BluetoothMessage::PairingResponse(_, _) => unimplemented!(),
+                BluetoothMessage::PairingResponse(_, _) => msg.log_unhandled(),
                 BluetoothMessage::PairingRequest(_chan, _m) => {
                     let mut m2 = Wifi::BluetoothPairingResponse::new();
                     m2.set_already_paired(true);
@@ -120,19 +124,21 @@ impl ChannelHandlerTrait for BluetoothChannelHandler {
         let msg3: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
         if let Ok(msg2) = msg3 {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::Bluetooth,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        todo!("{:02x?} {:?} {:?} ", msg, msg2, msg3);
+        msg.log_parse_errors(&errors);
+        Ok(())
     }
 }
@@ -0,0 +1,57 @@
+//! The source of the timestamps the crate puts in messages
+
+/// A source of wall clock time, for the timestamps in ping messages.
+/// Set [`crate::AndroidAutoConfiguration::clock`] to replace the system clock, for deterministic tests or to simulate clock skew.
+pub trait Clock: Send + Sync {
+    /// The current time in microseconds since the unix epoch
+    fn now_micros(&self) -> i64;
+}
+
+/// The system clock, used when no other clock is configured
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_micros(&self) -> i64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_micros() as i64
+    }
+}
+
+/// A clock that only moves when told to, for tests
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Debug, Default)]
+pub struct ManualClock {
+    /// The current time in microseconds since the unix epoch
+    now: std::sync::atomic::AtomicI64,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl ManualClock {
+    /// Construct a clock stopped at the specified time in microseconds since the unix epoch
+    pub fn new(micros: i64) -> Self {
+        Self {
+            now: std::sync::atomic::AtomicI64::new(micros),
+        }
+    }
+
+    /// Set the current time in microseconds since the unix epoch
+    pub fn set(&self, micros: i64) {
+        self.now.store(micros, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Move the clock forward by the specified amount
+    pub fn advance(&self, by: std::time::Duration) {
+        self.now
+            .fetch_add(by.as_micros() as i64, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Clock for ManualClock {
+    fn now_micros(&self) -> i64 {
+        self.now.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
@@ -1,7 +1,7 @@
 //! Messages common to all channels
 
-use super::{AndroidAutoFrame, ChannelId, FrameHeader, FrameHeaderContents, FrameHeaderType};
-use crate::Wifi;
+use super::{AndroidAutoFrame, ChannelId, FrameHeader, FrameHeaderContents};
+use crate::{ChannelKind, ChannelOpenError, FrameIoError, Wifi, WriteHalf};
 use protobuf::{Enum, Message};
 
 /// Messages common to all android auto channels.
@@ -16,14 +16,14 @@ pub enum AndroidAutoCommonMessage {
 impl TryFrom<&AndroidAutoFrame> for AndroidAutoCommonMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if value.header.frame.get_control() {
             let w = Wifi::CommonMessage::from_i32(ty as i32);
             if let Some(m) = w {
                 match m {
-                    Wifi::CommonMessage::CHANNEL_OPEN_RESPONSE => unimplemented!(),
+                    Wifi::CommonMessage::CHANNEL_OPEN_RESPONSE => {
+                        Err("Unsupported message CHANNEL_OPEN_RESPONSE".to_string())
+                    }
                     Wifi::CommonMessage::CHANNEL_OPEN_REQUEST => {
                         let m = Wifi::ChannelOpenRequest::parse_from_bytes(&value.data[2..]);
                         match m {
@@ -46,24 +46,95 @@ impl TryFrom<&AndroidAutoFrame> for AndroidAutoCommonMessage {
 
 impl From<AndroidAutoCommonMessage> for AndroidAutoFrame {
     fn from(value: AndroidAutoCommonMessage) -> Self {
-        match value {
-            AndroidAutoCommonMessage::ChannelOpenResponse(chan, m) => {
-                let mut data = m.write_to_bytes().unwrap();
-                let t = Wifi::CommonMessage::CHANNEL_OPEN_RESPONSE as u16;
-                let t = t.to_be_bytes();
-                let mut m = Vec::new();
-                m.push(t[0]);
-                m.push(t[1]);
-                m.append(&mut data);
-                AndroidAutoFrame {
-                    header: FrameHeader {
-                        channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, true),
-                    },
-                    data: m,
-                }
+        let (chan, t, mut data) = match value {
+            AndroidAutoCommonMessage::ChannelOpenResponse(chan, m) => (
+                chan,
+                Wifi::CommonMessage::CHANNEL_OPEN_RESPONSE,
+                m.write_to_bytes().unwrap(),
+            ),
+            AndroidAutoCommonMessage::ChannelOpenRequest(m) => (
+                m.channel_id() as ChannelId,
+                Wifi::CommonMessage::CHANNEL_OPEN_REQUEST,
+                m.write_to_bytes().unwrap(),
+            ),
+        };
+        let mut m = (t as u16).to_be_bytes().to_vec();
+        m.append(&mut data);
+        AndroidAutoFrame {
+            header: FrameHeader {
+                channel_id: chan,
+                frame: FrameHeaderContents::encrypted_common_single(),
+            },
+            data: m,
+        }
+    }
+}
+
+/// Open a channel with the user, remembering the result so the user is only asked once, unless the failure is transient.
+/// A transient failure is asked again on the next request from the compatible android auto device.
+pub(crate) async fn open_channel_once(
+    opened: &tokio::sync::OnceCell<bool>,
+    kind: ChannelKind,
+    open: impl Future<Output = Result<(), ChannelOpenError>>,
+) -> bool {
+    let r = opened
+        .get_or_try_init(|| async {
+            match open.await {
+                Ok(()) => Ok(true),
+                Err(ChannelOpenError::Permanent) => Ok(false),
+                Err(ChannelOpenError::Transient) => Err(()),
             }
-            AndroidAutoCommonMessage::ChannelOpenRequest(_) => unimplemented!(),
+        })
+        .await;
+    match r {
+        Ok(opened) => *opened,
+        Err(()) => {
+            log::warn!(
+                "{:?} channel is not available right now, the phone may ask again",
+                kind
+            );
+            false
         }
     }
 }
+
+/// Respond to a channel open request from the compatible android auto device, after running `open` to find out if the channel opened.
+/// The request, the status given and how long it took to respond are logged under the `android_auto::channel` target.
+pub(crate) async fn respond_channel_open(
+    kind: ChannelKind,
+    channel: ChannelId,
+    request: &Wifi::ChannelOpenRequest,
+    open: impl Future<Output = bool>,
+    stream: &WriteHalf,
+) -> Result<(), FrameIoError> {
+    let start = std::time::Instant::now();
+    log::debug!(
+        target: "android_auto::channel",
+        "{:?} channel {} open request: {:?}",
+        kind,
+        channel,
+        request
+    );
+    let status = if open.await {
+        Wifi::status::Enum::OK
+    } else {
+        Wifi::status::Enum::FAIL
+    };
+    let mut m2 = Wifi::ChannelOpenResponse::new();
+    m2.set_status(status);
+    stream
+        .write_frame(AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into())
+        .await?;
+    if status == Wifi::status::Enum::OK {
+        stream.state().channel_opened(channel, kind);
+    }
+    log::debug!(
+        target: "android_auto::channel",
+        "{:?} channel {} open response {:?} after {:?}",
+        kind,
+        channel,
+        status,
+        start.elapsed()
+    );
+    Ok(())
+}
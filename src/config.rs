@@ -0,0 +1,277 @@
+//! A representation of the configuration that can be loaded from a file with serde
+
+use std::path::PathBuf;
+
+use rustls::pki_types::{CertificateDer, pem::PemObject};
+
+use crate::{
+    AndroidAutoConfiguration, BluetoothProfileConfiguration, ClientCertificate, ClientError,
+    HeadUnitInfo, MediaRecorder, VideoConfiguration, VideoOrientation, Wifi,
+};
+
+/// Errors that can occur converting a configuration file into the runtime configuration
+#[derive(Debug)]
+pub enum ConfigError {
+    /// A file referenced by the configuration could not be read or created
+    Io(PathBuf, std::io::Error),
+    /// The client certificate or private key did not parse, or do not belong together
+    Certificate(ClientError),
+    /// A root certificate file did not parse
+    InvalidRoot(PathBuf),
+    /// The video resolution is not a valid [`Wifi::video_resolution::Enum`] value
+    InvalidResolution(i32),
+    /// The video fps is not a valid [`Wifi::video_fps::Enum`] value
+    InvalidFps(i32),
+    /// The maximum frame data size is zero or above [`AndroidAutoConfiguration::MAX_FRAME_DATA_SIZE_LIMIT`]
+    InvalidMaxFrameDataSize(usize),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(p, _) => write!(f, "error accessing {}", p.display()),
+            Self::Certificate(e) => write!(f, "{e}"),
+            Self::InvalidRoot(p) => write!(f, "{}: invalid root certificate", p.display()),
+            Self::InvalidResolution(r) => write!(f, "invalid video resolution {r}"),
+            Self::InvalidFps(r) => write!(f, "invalid video fps {r}"),
+            Self::InvalidMaxFrameDataSize(s) => write!(f, "invalid maximum frame data size {s}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(_, e) => Some(e),
+            Self::Certificate(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// The paths to the pem encoded client certificate and private key
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CertificateFiles {
+    /// The path to the certificate
+    pub certificate: PathBuf,
+    /// The path to the private key
+    pub private_key: PathBuf,
+}
+
+/// The serde form of [`AndroidAutoConfiguration`], convert it with `TryFrom`
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct AndroidAutoConfigFile {
+    /// The head unit information
+    pub unit: HeadUnitInfo,
+    /// The client certificate and private key (only if a custom one is desired)
+    #[serde(default)]
+    pub certificate: Option<CertificateFiles>,
+    /// Pem files with root certificates to trust in addition to the built in ones, see [`AndroidAutoConfiguration::extra_roots`]
+    #[serde(default)]
+    pub extra_roots: Vec<PathBuf>,
+    /// The directory to record received video and audio to (only if recording is desired), see [`MediaRecorder::to_directory`]
+    #[serde(default)]
+    pub recording_directory: Option<PathBuf>,
+    /// The idle timeout in seconds (only if idle disconnect is desired), see [`AndroidAutoConfiguration::idle_timeout`]
+    #[serde(default)]
+    pub idle_timeout_secs: Option<u64>,
+    /// The handshake timeout in seconds, see [`AndroidAutoConfiguration::handshake_timeout`]
+    #[serde(default)]
+    pub handshake_timeout_secs: Option<u64>,
+    /// The bluetooth profile advertised for wireless android auto, the standard android auto profile when absent
+    #[serde(default)]
+    pub bluetooth_profile: BluetoothProfileConfiguration,
+    /// Only send pings when the connection is otherwise idle, see [`AndroidAutoConfiguration::ping_only_when_idle`]
+    #[serde(default)]
+    pub ping_only_when_idle: bool,
+    /// The largest payload of a single frame, see [`AndroidAutoConfiguration::max_frame_data_size`]
+    #[serde(default)]
+    pub max_frame_data_size: Option<usize>,
+}
+
+impl TryFrom<AndroidAutoConfigFile> for AndroidAutoConfiguration {
+    type Error = ConfigError;
+
+    fn try_from(value: AndroidAutoConfigFile) -> Result<Self, Self::Error> {
+        let custom_certificate = match value.certificate {
+            Some(files) => {
+                let read = |p: PathBuf| std::fs::read(&p).map_err(|e| ConfigError::Io(p, e));
+                let cert =
+                    ClientCertificate::Pem(read(files.certificate)?, read(files.private_key)?);
+                cert.validate().map_err(ConfigError::Certificate)?;
+                Some(cert)
+            }
+            None => None,
+        };
+        let mut extra_roots = Vec::new();
+        for p in value.extra_roots {
+            let pem = std::fs::read(&p).map_err(|e| ConfigError::Io(p.clone(), e))?;
+            for c in CertificateDer::pem_slice_iter(&pem) {
+                extra_roots.push(c.map_err(|_| ConfigError::InvalidRoot(p.clone()))?);
+            }
+        }
+        let recorder = match value.recording_directory {
+            Some(dir) => {
+                Some(MediaRecorder::to_directory(&dir).map_err(|e| ConfigError::Io(dir, e))?)
+            }
+            None => None,
+        };
+        let limit = 1..=AndroidAutoConfiguration::MAX_FRAME_DATA_SIZE_LIMIT;
+        if let Some(s) = value.max_frame_data_size.filter(|s| !limit.contains(s)) {
+            return Err(ConfigError::InvalidMaxFrameDataSize(s));
+        }
+        Ok(Self {
+            unit: value.unit,
+            custom_certificate,
+            extra_roots,
+            recorder,
+            idle_timeout: value.idle_timeout_secs.map(std::time::Duration::from_secs),
+            handshake_timeout: value
+                .handshake_timeout_secs
+                .map(std::time::Duration::from_secs),
+            bluetooth_profile: value.bluetooth_profile,
+            clock: None,
+            overflow: Default::default(),
+            ping_only_when_idle: value.ping_only_when_idle,
+            max_frame_data_size: value.max_frame_data_size,
+        })
+    }
+}
+
+/// The serde form of [`VideoConfiguration`], convert it with `TryFrom`.
+/// The resolution and fps are the numeric values of [`Wifi::video_resolution::Enum`] and [`Wifi::video_fps::Enum`].
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+pub struct VideoConfigFile {
+    /// The resolution, see [`VideoConfiguration::resolution`]
+    pub resolution: i32,
+    /// The fps, see [`VideoConfiguration::fps`]
+    pub fps: i32,
+    /// The dots per inch of the display, see [`VideoConfiguration::dpi`]
+    pub dpi: u16,
+    /// See [`VideoConfiguration::margin_width`]
+    #[serde(default)]
+    pub margin_width: u16,
+    /// See [`VideoConfiguration::margin_height`]
+    #[serde(default)]
+    pub margin_height: u16,
+    /// See [`VideoConfiguration::orientation`]
+    #[serde(default)]
+    pub orientation: VideoOrientation,
+}
+
+impl TryFrom<VideoConfigFile> for VideoConfiguration {
+    type Error = ConfigError;
+
+    fn try_from(value: VideoConfigFile) -> Result<Self, Self::Error> {
+        use protobuf::Enum;
+        Ok(Self {
+            resolution: Wifi::video_resolution::Enum::from_i32(value.resolution)
+                .ok_or(ConfigError::InvalidResolution(value.resolution))?,
+            fps: Wifi::video_fps::Enum::from_i32(value.fps)
+                .ok_or(ConfigError::InvalidFps(value.fps))?,
+            dpi: value.dpi,
+            margin_width: value.margin_width,
+            margin_height: value.margin_height,
+            frame_drop: Default::default(),
+            packetization: Default::default(),
+            orientation: value.orientation,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockMain;
+    use crate::tests::{TEST_CERT, TEST_KEY};
+
+    /// A configuration file for the mock head unit
+    fn config_file() -> AndroidAutoConfigFile {
+        AndroidAutoConfigFile {
+            unit: MockMain::configuration().unit,
+            certificate: None,
+            extra_roots: Vec::new(),
+            recording_directory: None,
+            idle_timeout_secs: None,
+            handshake_timeout_secs: None,
+            bluetooth_profile: Default::default(),
+            ping_only_when_idle: false,
+            max_frame_data_size: None,
+        }
+    }
+
+    /// Write a file for a test to the temporary directory, returning its path
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("android-auto-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn certificates_are_loaded_from_their_files() {
+        let mut file = config_file();
+        file.certificate = Some(CertificateFiles {
+            certificate: temp_file("loaded.crt", TEST_CERT),
+            private_key: temp_file("loaded.key", TEST_KEY),
+        });
+        file.extra_roots = vec![temp_file("root.crt", TEST_CERT)];
+        file.idle_timeout_secs = Some(30);
+        let config = AndroidAutoConfiguration::try_from(file).unwrap();
+        let Some(ClientCertificate::Pem(cert, key)) = config.custom_certificate else {
+            panic!("the certificate was not loaded");
+        };
+        assert_eq!((cert, key), (TEST_CERT.into(), TEST_KEY.into()));
+        assert_eq!(config.extra_roots.len(), 1);
+        assert_eq!(
+            config.idle_timeout,
+            Some(std::time::Duration::from_secs(30))
+        );
+    }
+
+    #[test]
+    fn a_missing_certificate_file_is_named() {
+        let missing = std::env::temp_dir().join("android-auto-missing.crt");
+        let mut file = config_file();
+        file.certificate = Some(CertificateFiles {
+            certificate: missing.clone(),
+            private_key: temp_file("missing.key", TEST_KEY),
+        });
+        match AndroidAutoConfiguration::try_from(file) {
+            Err(ConfigError::Io(p, _)) => assert_eq!(p, missing),
+            _ => panic!("the missing certificate was not reported"),
+        }
+    }
+
+    #[test]
+    fn an_invalid_private_key_is_rejected() {
+        let mut file = config_file();
+        file.certificate = Some(CertificateFiles {
+            certificate: temp_file("invalid.crt", TEST_CERT),
+            private_key: temp_file("invalid.key", "not a key"),
+        });
+        assert!(matches!(
+            AndroidAutoConfiguration::try_from(file),
+            Err(ConfigError::Certificate(_))
+        ));
+    }
+
+    #[test]
+    fn video_values_are_checked() {
+        let video = VideoConfigFile {
+            resolution: Wifi::video_resolution::Enum::_720p as i32,
+            fps: 99,
+            dpi: 160,
+            margin_width: 0,
+            margin_height: 0,
+            orientation: Default::default(),
+        };
+        assert!(matches!(
+            VideoConfiguration::try_from(video.clone()),
+            Err(ConfigError::InvalidFps(99))
+        ));
+        let video = VideoConfiguration::try_from(VideoConfigFile { fps: 1, ..video }).unwrap();
+        assert_eq!(video.resolution, Wifi::video_resolution::Enum::_720p);
+        assert_eq!(video.fps, Wifi::video_fps::Enum::_30);
+    }
+}
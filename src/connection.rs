@@ -0,0 +1,715 @@
+//! A handle to a live connection with a compatible android auto device
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+};
+
+use crate::{
+    AvChannelMessage, ChannelKind, DrivingRestrictions, FrameIoError, SendableAndroidAutoMessage,
+    Wifi, WriteHalf, control::AndroidAutoControlMessage,
+};
+
+/// The classes of activity that keep a connection from being idle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ActivityClass {
+    /// Video received from the compatible android auto device
+    Video,
+    /// Audio received from the compatible android auto device, or audio input sent to it
+    Audio,
+    /// Input events sent to the compatible android auto device
+    Input,
+}
+
+impl ActivityClass {
+    /// All of the activity classes
+    pub const ALL: [ActivityClass; 3] = [
+        ActivityClass::Video,
+        ActivityClass::Audio,
+        ActivityClass::Input,
+    ];
+}
+
+/// The state of a live connection, updated by the channel handlers and read through [`Connection`]
+pub(crate) struct ConnectionState {
+    /// When the connection started
+    start: tokio::time::Instant,
+    /// The channels that have been opened successfully
+    open_channels: Mutex<Vec<(u8, ChannelKind)>>,
+    /// The number of frames received from the compatible android auto device
+    frames_received: AtomicU64,
+    /// The number of frames sent to the compatible android auto device
+    frames_sent: AtomicU64,
+    /// The audio focus state last given to the compatible android auto device
+    audio_focus: Mutex<Wifi::audio_focus_state::Enum>,
+    /// When each class of activity last happened
+    activity: Mutex<HashMap<ActivityClass, tokio::time::Instant>>,
+    /// The audio focus state from before projection was paused, while it is paused
+    paused: Mutex<Option<Wifi::audio_focus_state::Enum>>,
+    /// What the compatible android auto device has reported about itself so far
+    phone: Mutex<PhoneInfo>,
+    /// True once the ssl handshake is complete
+    handshake_complete: AtomicBool,
+    /// The handshake adjustments for the version of the compatible android auto device
+    quirks: Mutex<HandshakeQuirks>,
+    /// True once the head unit has asked for a shutdown because the connection was idle
+    idle_shutdown: AtomicBool,
+    /// The number of messages from the user waiting to be sent
+    outbound_queue_depth: AtomicUsize,
+    /// The number of sensor events from the user dropped by the overflow policy
+    sensor_events_dropped: AtomicU64,
+    /// The number of encrypted frames decrypted
+    decrypt_frames: AtomicU64,
+    /// The number of encrypted bytes decrypted
+    decrypt_bytes: AtomicU64,
+    /// The total time spent decrypting, in nanoseconds
+    decrypt_total: AtomicU64,
+    /// The shortest time taken to decrypt a frame, in nanoseconds, [`u64::MAX`] until a frame is decrypted
+    decrypt_min: AtomicU64,
+    /// The longest time taken to decrypt a frame, in nanoseconds
+    decrypt_max: AtomicU64,
+    /// When a frame was last sent or received, in nanoseconds since the connection started
+    last_traffic: AtomicU64,
+    /// The service discovery response sent to the compatible android auto device
+    service_discovery: Mutex<Option<Wifi::ServiceDiscoveryResponse>>,
+    /// The driving restrictions from the last driving status sent
+    driving_restrictions: Mutex<Option<DrivingRestrictions>>,
+    /// True for night and false for day, from the last night mode sent
+    night_mode: Mutex<Option<bool>>,
+}
+
+impl ConnectionState {
+    /// Construct the state for a new connection
+    pub fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            open_channels: Mutex::new(Vec::new()),
+            frames_received: AtomicU64::new(0),
+            frames_sent: AtomicU64::new(0),
+            audio_focus: Mutex::new(Wifi::audio_focus_state::Enum::NONE),
+            activity: Mutex::new(HashMap::new()),
+            paused: Mutex::new(None),
+            phone: Mutex::new(PhoneInfo::default()),
+            handshake_complete: AtomicBool::new(false),
+            quirks: Mutex::new(HandshakeQuirks::default()),
+            idle_shutdown: AtomicBool::new(false),
+            outbound_queue_depth: AtomicUsize::new(0),
+            sensor_events_dropped: AtomicU64::new(0),
+            decrypt_frames: AtomicU64::new(0),
+            decrypt_bytes: AtomicU64::new(0),
+            decrypt_total: AtomicU64::new(0),
+            decrypt_min: AtomicU64::new(u64::MAX),
+            decrypt_max: AtomicU64::new(0),
+            last_traffic: AtomicU64::new(0),
+            service_discovery: Mutex::new(None),
+            driving_restrictions: Mutex::new(None),
+            night_mode: Mutex::new(None),
+        }
+    }
+
+    /// Record that the ssl handshake is complete
+    pub fn handshake_completed(&self) {
+        self.handshake_complete.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true once the ssl handshake is complete
+    pub fn is_handshake_complete(&self) -> bool {
+        self.handshake_complete.load(Ordering::Relaxed)
+    }
+
+    /// Set the handshake adjustments for the version of the compatible android auto device
+    pub fn set_handshake_quirks(&self, quirks: HandshakeQuirks) {
+        *self.quirks.lock().unwrap() = quirks;
+    }
+
+    /// The handshake adjustments in effect
+    pub fn handshake_quirks(&self) -> HandshakeQuirks {
+        self.quirks.lock().unwrap().clone()
+    }
+
+    /// Record that the head unit asked for a shutdown because the connection was idle
+    pub fn idle_shutdown_requested(&self) {
+        self.idle_shutdown.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true when the head unit asked for a shutdown because the connection was idle
+    pub fn is_idle_shutdown(&self) -> bool {
+        self.idle_shutdown.load(Ordering::Relaxed)
+    }
+
+    /// How long the connection has been up
+    pub fn uptime(&self) -> std::time::Duration {
+        self.start.elapsed()
+    }
+
+    /// Record the audio focus state given to the compatible android auto device
+    pub fn set_audio_focus(&self, state: Wifi::audio_focus_state::Enum) {
+        *self.audio_focus.lock().unwrap() = state;
+    }
+
+    /// Update what is known about the compatible android auto device
+    pub fn update_phone_info(&self, f: impl FnOnce(&mut PhoneInfo)) {
+        f(&mut self.phone.lock().unwrap());
+    }
+
+    /// Record the service discovery response sent to the compatible android auto device, returning true if one was sent before
+    pub fn service_discovery_sent(&self, response: Wifi::ServiceDiscoveryResponse) -> bool {
+        self.service_discovery
+            .lock()
+            .unwrap()
+            .replace(response)
+            .is_some()
+    }
+
+    /// Record the driving restrictions of a driving status sent to the compatible android auto device
+    pub fn set_driving_restrictions(&self, r: DrivingRestrictions) {
+        *self.driving_restrictions.lock().unwrap() = Some(r);
+    }
+
+    /// Record the night mode of a sensor event sent to the compatible android auto device, or set for when the sensor is started.
+    /// Returns the night mode it replaces.
+    pub fn set_night_mode(&self, night: bool) -> Option<bool> {
+        self.night_mode.lock().unwrap().replace(night)
+    }
+
+    /// The night mode last sent or set, true for night
+    pub fn night_mode(&self) -> Option<bool> {
+        *self.night_mode.lock().unwrap()
+    }
+
+    /// Record that a channel was opened
+    pub fn channel_opened(&self, channel: u8, kind: ChannelKind) {
+        let mut open = self.open_channels.lock().unwrap();
+        if !open.iter().any(|(c, _)| *c == channel) {
+            open.push((channel, kind));
+        }
+    }
+
+    /// Returns true when the specified channel has been opened
+    pub fn is_channel_open(&self, channel: u8) -> bool {
+        self.open_channels
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(c, _)| *c == channel)
+    }
+
+    /// Record that a frame was received on the specified channel
+    pub fn frame_received(&self, channel: u8) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.traffic();
+        let kind = self
+            .open_channels
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(c, _)| *c == channel)
+            .map(|(_, k)| *k);
+        let class = match kind {
+            Some(ChannelKind::Video) => Some(ActivityClass::Video),
+            Some(ChannelKind::MediaAudio | ChannelKind::SpeechAudio | ChannelKind::SystemAudio) => {
+                Some(ActivityClass::Audio)
+            }
+            _ => None,
+        };
+        if let Some(class) = class {
+            self.activity(class);
+        }
+    }
+
+    /// Record that the specified class of activity happened
+    pub fn activity(&self, class: ActivityClass) {
+        self.activity
+            .lock()
+            .unwrap()
+            .insert(class, tokio::time::Instant::now());
+    }
+
+    /// How long it has been since the specified class of activity happened, or since the connection started if it never has
+    pub fn idle_for(&self, class: ActivityClass) -> std::time::Duration {
+        self.activity
+            .lock()
+            .unwrap()
+            .get(&class)
+            .unwrap_or(&self.start)
+            .elapsed()
+    }
+
+    /// How long it has been since any class of activity happened
+    pub fn idle_time(&self) -> std::time::Duration {
+        ActivityClass::ALL
+            .iter()
+            .map(|c| self.idle_for(*c))
+            .min()
+            .unwrap_or_default()
+    }
+
+    /// Record that a frame was sent or received
+    fn traffic(&self) {
+        self.last_traffic
+            .store(self.start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// How long it has been since a frame was sent or received, or since the connection started if none has been
+    pub fn traffic_idle_time(&self) -> std::time::Duration {
+        let last = std::time::Duration::from_nanos(self.last_traffic.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// Record the number of messages from the user waiting to be sent
+    pub fn set_outbound_queue_depth(&self, depth: usize) {
+        self.outbound_queue_depth.store(depth, Ordering::Relaxed);
+    }
+
+    /// Record that the overflow policy dropped a sensor event from the user
+    pub fn sensor_event_dropped(&self) {
+        self.sensor_events_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that an encrypted frame of the specified size took the specified time to decrypt
+    pub fn frame_decrypted(&self, bytes: usize, took: std::time::Duration) {
+        let nanos = took.as_nanos() as u64;
+        self.decrypt_frames.fetch_add(1, Ordering::Relaxed);
+        self.decrypt_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        self.decrypt_total.fetch_add(nanos, Ordering::Relaxed);
+        self.decrypt_min.fetch_min(nanos, Ordering::Relaxed);
+        self.decrypt_max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// The timing of decrypting frames so far
+    fn decrypt_stats(&self) -> DecryptStats {
+        let frames = self.decrypt_frames.load(Ordering::Relaxed);
+        let min = if frames == 0 {
+            0
+        } else {
+            self.decrypt_min.load(Ordering::Relaxed)
+        };
+        DecryptStats {
+            frames,
+            bytes: self.decrypt_bytes.load(Ordering::Relaxed),
+            total: std::time::Duration::from_nanos(self.decrypt_total.load(Ordering::Relaxed)),
+            min: std::time::Duration::from_nanos(min),
+            max: std::time::Duration::from_nanos(self.decrypt_max.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Record that a frame was written to the compatible android auto device
+    pub fn frame_sent(&self) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.traffic();
+    }
+
+    /// The number of frames written to the compatible android auto device
+    #[cfg(test)]
+    pub(crate) fn frames_sent(&self) -> u64 {
+        self.frames_sent.load(Ordering::Relaxed)
+    }
+}
+
+/// The compatibility status from the version response of the compatible android auto device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VersionStatus {
+    /// Status 0, the device supports the version requested by the head unit
+    Match,
+    /// Status 0xffff, the device does not support the version requested by the head unit and the connection is abandoned
+    Mismatch,
+    /// Any other status. The meaning is not documented, it is taken as a warning that the match is only partial, and the connection continues
+    Other(u16),
+}
+
+impl From<u16> for VersionStatus {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Self::Match,
+            0xffff => Self::Mismatch,
+            s => Self::Other(s),
+        }
+    }
+}
+
+/// Adjustments to the handshake for a particular protocol version of the compatible android auto device,
+/// chosen by [`crate::AndroidAutoMainTrait::handshake_quirks`] once the version response arrives.
+/// The head unit requests version 1.1, and every phone tested so far has accepted the same handshake regardless of the version it answers with,
+/// so the default for every version changes nothing. The fields exist for integrators to work around specific phones.
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeQuirks {
+    /// How long to wait after the ssl handshake completes before sending the auth complete indication.
+    /// None sends it immediately, which is what all tested phones expect.
+    pub auth_complete_delay: Option<std::time::Duration>,
+}
+
+impl HandshakeQuirks {
+    /// The quirks built into this crate for the specified protocol version, currently none for any version
+    pub fn for_version(_major: u16, _minor: u16) -> Self {
+        Self::default()
+    }
+}
+
+/// What the compatible android auto device has reported about itself, filled in as the messages carrying it arrive.
+/// Each field is empty until then.
+#[derive(Clone, Debug, Default)]
+pub struct PhoneInfo {
+    /// The protocol version (major, minor) from the version response
+    pub version: Option<(u16, u16)>,
+    /// The compatibility status from the version response
+    pub version_status: Option<VersionStatus>,
+    /// The name of the device from the service discovery request
+    pub device_name: Option<String>,
+    /// The brand of the device from the service discovery request
+    pub device_brand: Option<String>,
+    /// The channels the device has opened successfully, with their channel ids
+    pub channels: Vec<(u8, ChannelKind)>,
+    /// The sensors the device has started successfully
+    pub sensors: Vec<Wifi::sensor_type::Enum>,
+}
+
+/// Statistics for a live connection
+#[derive(Clone, Debug)]
+pub struct ConnectionStats {
+    /// How long the connection has been up
+    pub uptime: std::time::Duration,
+    /// The number of frames received from the compatible android auto device
+    pub frames_received: u64,
+    /// The number of frames sent to the compatible android auto device
+    pub frames_sent: u64,
+    /// The number of messages from the user waiting to be sent, as of the last one sent
+    pub outbound_queue_depth: usize,
+    /// The number of sensor events from the user dropped by [`crate::OverflowPolicy::DropOldestSensor`]
+    pub sensor_events_dropped: u64,
+    /// The timing of decrypting the encrypted frames received, most of which carry video
+    pub decrypt: DecryptStats,
+}
+
+/// The time spent decrypting frames received from the compatible android auto device.
+/// A maximum or average approaching the frame interval of the video stream points to decrypting as the cause of dropped frames.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DecryptStats {
+    /// The number of frames decrypted
+    pub frames: u64,
+    /// The number of encrypted bytes decrypted
+    pub bytes: u64,
+    /// The total time spent decrypting
+    pub total: std::time::Duration,
+    /// The shortest time taken to decrypt a frame, zero when no frame has been decrypted
+    pub min: std::time::Duration,
+    /// The longest time taken to decrypt a frame
+    pub max: std::time::Duration,
+}
+
+impl DecryptStats {
+    /// The average time taken to decrypt a frame, or None when no frame has been decrypted
+    pub fn average(&self) -> Option<std::time::Duration> {
+        if self.frames == 0 {
+            None
+        } else {
+            let nanos = self.total.as_nanos() / self.frames as u128;
+            Some(std::time::Duration::from_nanos(nanos as u64))
+        }
+    }
+}
+
+/// A handle to a live connection with a compatible android auto device, given to [`crate::AndroidAutoMainTrait::connection_started`].
+/// It can be cloned and kept for as long as desired, once the connection ends sending fails.
+#[derive(Clone)]
+pub struct Connection {
+    /// The stream for sending to the compatible android auto device
+    stream: WriteHalf,
+}
+
+impl Connection {
+    /// Construct a handle for the connection that the stream writes to
+    pub(crate) fn new(stream: WriteHalf) -> Self {
+        Self { stream }
+    }
+
+    /// The shared state of the connection
+    fn state(&self) -> &Arc<ConnectionState> {
+        self.stream.state()
+    }
+
+    /// Send a message to the compatible android auto device
+    pub async fn send(&self, m: SendableAndroidAutoMessage) -> Result<(), FrameIoError> {
+        Ok(self.stream.write_message(m).await?)
+    }
+
+    /// Returns true while the connection is still up
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_connected()
+    }
+
+    /// The channels that the compatible android auto device has opened successfully, with their channel ids
+    pub fn open_channels(&self) -> Vec<(u8, ChannelKind)> {
+        self.state().open_channels.lock().unwrap().clone()
+    }
+
+    /// Returns true when a channel of the given kind has been opened successfully
+    pub fn is_open(&self, kind: ChannelKind) -> bool {
+        self.state()
+            .open_channels
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, k)| *k == kind)
+    }
+
+    /// What the compatible android auto device has reported about itself so far
+    pub fn phone_info(&self) -> PhoneInfo {
+        let mut info = self.state().phone.lock().unwrap().clone();
+        info.channels = self.open_channels();
+        info
+    }
+
+    /// The latest service discovery response sent to the compatible android auto device, None until it has been sent.
+    /// This is exactly what was advertised (the channels, head unit information and flags), for checking it against the configuration.
+    pub fn service_discovery_response(&self) -> Option<Wifi::ServiceDiscoveryResponse> {
+        self.state().service_discovery.lock().unwrap().clone()
+    }
+
+    /// The driving restrictions in effect, from the last driving status sent with [`crate::AndroidAutoMessage::driving_status`]
+    /// or any other sensor event carrying one. None until a driving status has been sent.
+    pub fn driving_restrictions(&self) -> Option<DrivingRestrictions> {
+        *self.state().driving_restrictions.lock().unwrap()
+    }
+
+    /// The night mode of the projection, true for night and false for day. None until it has been set with [`Self::set_night_mode`]
+    /// or sent in any sensor event carrying a night mode.
+    pub fn night_mode(&self) -> Option<bool> {
+        self.state().night_mode()
+    }
+
+    /// Switch the projection between night and day, like the lights of the car do.
+    /// Calling this with the mode already in effect sends nothing, so it can be called whenever the light sensor is read.
+    /// The event is only sent once the compatible android auto device has started the night data sensor,
+    /// before that the mode is kept and sent as soon as the sensor is started.
+    /// [`Wifi::sensor_type::Enum::NIGHT_DATA`] must be advertised in the [`crate::SensorInformation`].
+    /// Raw sensor events carrying a night mode, such as from [`crate::AndroidAutoMessage::night_mode`], update the same state,
+    /// and like them this is subject to the minimum interval of the sensor: a switch sooner than that after the previous event
+    /// is held back and sent once the interval has elapsed, so the phone always ends up with the latest mode.
+    /// The driving status works the same way, with [`crate::AndroidAutoMessage::driving_status`] and [`Self::driving_restrictions`].
+    pub async fn set_night_mode(&self, night: bool) -> Result<(), FrameIoError> {
+        if self.state().set_night_mode(night) == Some(night) {
+            return Ok(());
+        }
+        let started = self
+            .state()
+            .phone
+            .lock()
+            .unwrap()
+            .sensors
+            .contains(&Wifi::sensor_type::Enum::NIGHT_DATA);
+        if !started {
+            return Ok(());
+        }
+        self.send(crate::AndroidAutoMessage::night_mode(night).sendable())
+            .await
+    }
+
+    /// The audio focus state last given to the compatible android auto device, NONE until it first requests audio focus
+    pub fn audio_focus(&self) -> Wifi::audio_focus_state::Enum {
+        *self.state().audio_focus.lock().unwrap()
+    }
+
+    /// How long it has been since the specified class of activity happened, or since the connection started if it never has
+    pub fn idle_for(&self, class: ActivityClass) -> std::time::Duration {
+        self.state().idle_for(class)
+    }
+
+    /// Tell the compatible android auto device whether the video channel has focus, without it having asked
+    async fn send_video_focus(
+        &self,
+        focus: Wifi::video_focus_mode::Enum,
+    ) -> Result<(), FrameIoError> {
+        let channel = self
+            .open_channels()
+            .into_iter()
+            .find(|(_, k)| *k == ChannelKind::Video)
+            .map(|(c, _)| c);
+        if let Some(channel) = channel {
+            let mut m = Wifi::VideoFocusIndication::new();
+            m.set_focus_mode(focus);
+            m.set_unrequested(true);
+            self.stream
+                .write_frame(AvChannelMessage::VideoIndicationResponse(channel, m).into())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Give the compatible android auto device an audio focus state, without it having asked
+    async fn send_audio_focus(&self, s: Wifi::audio_focus_state::Enum) -> Result<(), FrameIoError> {
+        let mut m = Wifi::AudioFocusResponse::new();
+        m.set_audio_focus_state(s);
+        self.stream
+            .write_frame(AndroidAutoControlMessage::AudioFocusResponse(m).into())
+            .await?;
+        self.state().set_audio_focus(s);
+        Ok(())
+    }
+
+    /// Send a message on the control channel, for experimenting with control behavior without changing the crate.
+    /// Nothing the crate tracks (such as the audio focus state) is updated, so the crate and the phone can disagree afterwards.
+    /// Messages that only travel from the compatible android auto device return [`FrameIoError::Unsendable`].
+    #[cfg(feature = "unstable")]
+    pub async fn send_control(&self, m: AndroidAutoControlMessage) -> Result<(), FrameIoError> {
+        if !m.is_sendable() {
+            return Err(FrameIoError::Unsendable(format!("{:?}", m)));
+        }
+        self.stream.write_frame(m.into()).await?;
+        Ok(())
+    }
+
+    /// Pause projection, for when something else takes over the display and speakers (such as a reverse camera).
+    /// This sends, in order:
+    /// 1. a VideoFocusIndication with focus mode UNFOCUSED on the video channel, if it is open
+    /// 2. an AudioFocusResponse with state LOSS_TRANSIENT on the control channel, if the phone currently holds audio focus
+    ///
+    /// The phone stops sending video and audio until [`Self::resume`] is called. Pausing while paused does nothing.
+    pub async fn pause(&self) -> Result<(), FrameIoError> {
+        let focus = {
+            let mut paused = self.state().paused.lock().unwrap();
+            if paused.is_some() {
+                return Ok(());
+            }
+            let focus = self.audio_focus();
+            paused.replace(focus);
+            focus
+        };
+        self.send_video_focus(Wifi::video_focus_mode::Enum::UNFOCUSED)
+            .await?;
+        if Self::holds_audio_focus(focus) {
+            self.send_audio_focus(Wifi::audio_focus_state::Enum::LOSS_TRANSIENT)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Resume projection after [`Self::pause`]. This sends, in order:
+    /// 1. a VideoFocusIndication with focus mode FOCUSED on the video channel, if it is open
+    /// 2. an AudioFocusResponse restoring the audio focus state from before the pause, if the phone held audio focus then
+    ///
+    /// Resuming while not paused does nothing.
+    pub async fn resume(&self) -> Result<(), FrameIoError> {
+        let focus = self.state().paused.lock().unwrap().take();
+        let Some(focus) = focus else {
+            return Ok(());
+        };
+        self.send_video_focus(Wifi::video_focus_mode::Enum::FOCUSED)
+            .await?;
+        if Self::holds_audio_focus(focus) {
+            self.send_audio_focus(focus).await?;
+        }
+        Ok(())
+    }
+
+    /// Restart the video stream, for recovering from a degraded link.
+    /// The protocol has no message for asking the phone to change the video bitrate or quality, so this cycles the video focus instead:
+    /// a VideoFocusIndication with focus mode UNFOCUSED followed by one with FOCUSED, both on the video channel.
+    /// The phone restarts its encoder in response and sends a fresh keyframe, which clears corruption left by lost data.
+    /// It does not lower the quality, that is only possible by advertising a lower resolution or fps in [`crate::VideoConfiguration`] for the next connection.
+    /// Does nothing while projection is paused.
+    pub async fn cycle_video_focus(&self) -> Result<(), FrameIoError> {
+        if self.is_paused() {
+            return Ok(());
+        }
+        self.send_video_focus(Wifi::video_focus_mode::Enum::UNFOCUSED)
+            .await?;
+        self.send_video_focus(Wifi::video_focus_mode::Enum::FOCUSED)
+            .await
+    }
+
+    /// Returns true when projection is paused with [`Self::pause`]
+    pub fn is_paused(&self) -> bool {
+        self.state().paused.lock().unwrap().is_some()
+    }
+
+    /// Returns true when the audio focus state means the phone is allowed to play audio
+    fn holds_audio_focus(s: Wifi::audio_focus_state::Enum) -> bool {
+        !matches!(
+            s,
+            Wifi::audio_focus_state::Enum::NONE
+                | Wifi::audio_focus_state::Enum::LOSS
+                | Wifi::audio_focus_state::Enum::LOSS_TRANSIENT
+        )
+    }
+
+    /// Retrieve the statistics of the connection so far
+    pub fn stats(&self) -> ConnectionStats {
+        let state = self.state();
+        ConnectionStats {
+            uptime: state.uptime(),
+            frames_received: state.frames_received.load(Ordering::Relaxed),
+            frames_sent: state.frames_sent.load(Ordering::Relaxed),
+            outbound_queue_depth: state.outbound_queue_depth.load(Ordering::Relaxed),
+            sensor_events_dropped: state.sensor_events_dropped.load(Ordering::Relaxed),
+            decrypt: state.decrypt_stats(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::response;
+
+    #[tokio::test]
+    async fn pause_and_resume_move_video_and_audio_focus() {
+        let (stream, mut sent) = WriteHalf::capturing();
+        stream.state().channel_opened(1, ChannelKind::Video);
+        stream
+            .state()
+            .set_audio_focus(Wifi::audio_focus_state::Enum::GAIN);
+        let connection = Connection::new(stream);
+        let video = Wifi::avchannel_message::Enum::VIDEO_FOCUS_INDICATION as u16;
+        let audio = Wifi::ControlMessage::AUDIO_FOCUS_RESPONSE as u16;
+
+        connection.pause().await.unwrap();
+        connection.pause().await.unwrap();
+        let frames = sent.frames();
+        assert_eq!(
+            frames
+                .iter()
+                .map(|f| f.header.channel_id)
+                .collect::<Vec<_>>(),
+            vec![1, 0]
+        );
+        let m: Wifi::VideoFocusIndication = response(&frames, video);
+        assert_eq!(m.focus_mode(), Wifi::video_focus_mode::Enum::UNFOCUSED);
+        let m: Wifi::AudioFocusResponse = response(&frames, audio);
+        assert_eq!(
+            m.audio_focus_state(),
+            Wifi::audio_focus_state::Enum::LOSS_TRANSIENT
+        );
+        assert!(connection.is_paused());
+
+        connection.resume().await.unwrap();
+        connection.resume().await.unwrap();
+        let frames = sent.frames();
+        assert_eq!(frames.len(), 2);
+        let m: Wifi::VideoFocusIndication = response(&frames, video);
+        assert_eq!(m.focus_mode(), Wifi::video_focus_mode::Enum::FOCUSED);
+        let m: Wifi::AudioFocusResponse = response(&frames, audio);
+        assert_eq!(m.audio_focus_state(), Wifi::audio_focus_state::Enum::GAIN);
+        assert_eq!(
+            connection.audio_focus(),
+            Wifi::audio_focus_state::Enum::GAIN
+        );
+        assert!(!connection.is_paused());
+    }
+
+    #[test]
+    fn average_decrypt_time_counts_every_frame() {
+        // more frames than fit in a u32
+        let frames = (1 << 32) + 2;
+        let stats = DecryptStats {
+            frames,
+            total: std::time::Duration::from_nanos(frames * 3),
+            ..Default::default()
+        };
+        assert_eq!(stats.average(), Some(std::time::Duration::from_nanos(3)));
+        assert_eq!(DecryptStats::default().average(), None);
+    }
+}
@@ -1,7 +1,7 @@
 //! Code for the control channel
 
 use super::VERSION;
-use super::{AndroidAutoFrame, FrameHeader, FrameHeaderContents, FrameHeaderType};
+use super::{AndroidAutoFrame, FrameHeader, FrameHeaderContents};
 use crate::{
     AndroidAutoConfiguration, AndroidAutoMainTrait, ChannelHandlerTrait, ChannelId, StreamMux, Wifi,
 };
@@ -18,8 +18,8 @@ pub enum AndroidAutoControlMessage {
         major: u16,
         /// The minor version
         minor: u16,
-        /// The status of the version compatibility, 0xffff indicates incompatibility
-        status: u16,
+        /// The status of the version compatibility
+        status: crate::VersionStatus,
     },
     /// A message containing ssl handshake data
     SslHandshake(Vec<u8>),
@@ -49,20 +49,48 @@ pub enum AndroidAutoControlMessage {
     VoiceSession(Wifi::VoiceSessionRequest),
 }
 
+impl AndroidAutoControlMessage {
+    /// Returns true when the message can be converted into a frame and sent by the head unit.
+    /// The messages that only travel from the compatible android auto device cannot.
+    pub fn is_sendable(&self) -> bool {
+        !matches!(
+            self,
+            Self::VoiceSession(_)
+                | Self::NavigationFocusRequest(_)
+                | Self::AudioFocusRequest(_)
+                | Self::ServiceDiscoveryRequest(_)
+                | Self::VersionResponse { .. }
+        )
+    }
+
+    /// A ping request stamped with the current time of the configured clock
+    pub(crate) fn ping_request(config: &AndroidAutoConfiguration) -> Self {
+        let mut m = Wifi::PingRequest::new();
+        m.set_timestamp(config.now_micros());
+        Self::PingRequest(m)
+    }
+}
+
 impl TryFrom<&AndroidAutoFrame> for AndroidAutoControlMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if !value.header.frame.get_control() {
             let w = Wifi::ControlMessage::from_i32(ty as i32);
             if let Some(m) = w {
                 match m {
-                    Wifi::ControlMessage::VERSION_REQUEST => unimplemented!(),
-                    Wifi::ControlMessage::AUTH_COMPLETE => unimplemented!(),
-                    Wifi::ControlMessage::MESSAGE_NONE => unimplemented!(),
-                    Wifi::ControlMessage::SERVICE_DISCOVERY_RESPONSE => unimplemented!(),
+                    Wifi::ControlMessage::VERSION_REQUEST => {
+                        Err("Unsupported message VERSION_REQUEST".to_string())
+                    }
+                    Wifi::ControlMessage::AUTH_COMPLETE => {
+                        Err("Unsupported message AUTH_COMPLETE".to_string())
+                    }
+                    Wifi::ControlMessage::MESSAGE_NONE => {
+                        Err("Unsupported message MESSAGE_NONE".to_string())
+                    }
+                    Wifi::ControlMessage::SERVICE_DISCOVERY_RESPONSE => {
+                        Err("Unsupported message SERVICE_DISCOVERY_RESPONSE".to_string())
+                    }
                     Wifi::ControlMessage::PING_REQUEST => {
                         let m = Wifi::PingRequest::parse_from_bytes(&value.data[2..]);
                         match m {
@@ -77,7 +105,9 @@ impl TryFrom<&AndroidAutoFrame> for AndroidAutoControlMessage {
                             Err(e) => Err(format!("Invalid request: {}", e)),
                         }
                     }
-                    Wifi::ControlMessage::NAVIGATION_FOCUS_RESPONSE => unimplemented!(),
+                    Wifi::ControlMessage::NAVIGATION_FOCUS_RESPONSE => {
+                        Err("Unsupported message NAVIGATION_FOCUS_RESPONSE".to_string())
+                    }
                     Wifi::ControlMessage::SHUTDOWN_REQUEST => {
                         let m = Wifi::ShutdownRequest::parse_from_bytes(&value.data[2..]);
                         match m {
@@ -85,7 +115,9 @@ impl TryFrom<&AndroidAutoFrame> for AndroidAutoControlMessage {
                             Err(e) => Err(format!("Invalid shutdown request: {}", e)),
                         }
                     }
-                    Wifi::ControlMessage::SHUTDOWN_RESPONSE => unimplemented!(),
+                    Wifi::ControlMessage::SHUTDOWN_RESPONSE => {
+                        Ok(AndroidAutoControlMessage::ShutdownResponse)
+                    }
                     Wifi::ControlMessage::VOICE_SESSION_REQUEST => {
                         let m = Wifi::VoiceSessionRequest::parse_from_bytes(&value.data[2..]);
                         match m {
@@ -93,7 +125,9 @@ impl TryFrom<&AndroidAutoFrame> for AndroidAutoControlMessage {
                             Err(e) => Err(format!("Invalid ping response: {}", e)),
                         }
                     }
-                    Wifi::ControlMessage::AUDIO_FOCUS_RESPONSE => unimplemented!(),
+                    Wifi::ControlMessage::AUDIO_FOCUS_RESPONSE => {
+                        Err("Unsupported message AUDIO_FOCUS_RESPONSE".to_string())
+                    }
                     Wifi::ControlMessage::PING_RESPONSE => {
                         let m = Wifi::PingResponse::parse_from_bytes(&value.data[2..]);
                         match m {
@@ -116,7 +150,7 @@ impl TryFrom<&AndroidAutoFrame> for AndroidAutoControlMessage {
                             Ok(AndroidAutoControlMessage::VersionResponse {
                                 major,
                                 minor,
-                                status,
+                                status: status.into(),
                             })
                         } else {
                             Err("Invalid version response packet".to_string())
@@ -161,12 +195,27 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
+                    },
+                    data: m,
+                }
+            }
+            AndroidAutoControlMessage::ShutdownRequest(m) => {
+                let mut data = m.write_to_bytes().unwrap();
+                let t = Wifi::ControlMessage::SHUTDOWN_REQUEST as u16;
+                let t = t.to_be_bytes();
+                let mut m = Vec::new();
+                m.push(t[0]);
+                m.push(t[1]);
+                m.append(&mut data);
+                AndroidAutoFrame {
+                    header: FrameHeader {
+                        channel_id: 0,
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
             }
-            AndroidAutoControlMessage::ShutdownRequest(_) => unimplemented!(),
             AndroidAutoControlMessage::ShutdownResponse => {
                 let m = Wifi::ShutdownResponse::new();
                 let mut data = m.write_to_bytes().unwrap();
@@ -179,7 +228,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -195,7 +244,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::plaintext_single(),
                     },
                     data: m,
                 }
@@ -211,7 +260,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::plaintext_single(),
                     },
                     data: m,
                 }
@@ -227,7 +276,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -244,7 +293,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -264,7 +313,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::plaintext_single(),
                     },
                     data: m,
                 }
@@ -279,7 +328,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::plaintext_single(),
                     },
                     data: m,
                 }
@@ -302,7 +351,7 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: 0,
-                        frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::plaintext_single(),
                     },
                     data: m,
                 }
@@ -321,7 +370,9 @@ impl From<AndroidAutoControlMessage> for AndroidAutoFrame {
 
 /// The inner data for the channel handler
 struct InnerChannelHandler {
-    /// The list of all channels for the head unit. This is filled out after the control channel is created
+    /// The list of all channels for the head unit. This is filled out after the control channel is created.
+    /// The channel ids are the positions of the channel handlers, which are fixed for the whole connection,
+    /// so every service discovery request in a connection is answered with these same channels.
     channels: Vec<Wifi::ChannelDescriptor>,
 }
 
@@ -371,13 +422,32 @@ impl ChannelHandlerTrait for ControlChannelHandler {
         config: &AndroidAutoConfiguration,
         main: &T,
     ) -> Result<(), super::FrameIoError> {
-        let msg2: Result<AndroidAutoControlMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<super::AndroidAutoCommonMessage>(&mut errors) {
+            match msg2 {
+                super::AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                super::AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    // The control channel is always open and cannot be opened again, so the request is refused.
+                    // Such frames are normally rejected by the control bit check before reaching this handler.
+                    log::warn!("Refusing a channel open request on the control channel");
+                    super::respond_channel_open(
+                        crate::ChannelKind::Control,
+                        0,
+                        &m,
+                        async { false },
+                        stream,
+                    )
+                    .await?;
+                }
+            }
+            return Ok(());
+        }
+        if let Some(msg2) = msg.parse::<AndroidAutoControlMessage>(&mut errors) {
             match msg2 {
                 AndroidAutoControlMessage::VoiceSession(m) => {
                     log::error!("Received voice session request {:?}", m);
                 }
-                AndroidAutoControlMessage::NavigationFocusResponse(_) => unimplemented!(),
+                AndroidAutoControlMessage::NavigationFocusResponse(_) => msg.log_unhandled(),
                 AndroidAutoControlMessage::NavigationFocusRequest(m) => {
                     log::error!("Received navigation focus request {}", m.type_());
                     let mut m2 = Wifi::NavigationFocusResponse::new();
@@ -386,22 +456,22 @@ impl ChannelHandlerTrait for ControlChannelHandler {
                         .write_frame(AndroidAutoControlMessage::NavigationFocusResponse(m2).into())
                         .await?;
                 }
-                AndroidAutoControlMessage::ShutdownResponse => unimplemented!(),
+                AndroidAutoControlMessage::ShutdownResponse => {
+                    log::info!("Shutdown accepted by the phone");
+                    return Err(super::FrameIoError::ShutdownRequested);
+                }
                 AndroidAutoControlMessage::ShutdownRequest(m) => {
-                    if m.reason() == Wifi::shutdown_reason::Enum::QUIT {
-                        stream
-                            .write_frame(AndroidAutoControlMessage::ShutdownResponse.into())
-                            .await?;
-                        return Err(super::FrameIoError::ShutdownRequested);
-                    }
+                    // The phone stops the session after a shutdown request whatever the reason, so every reason ends the connection
+                    log::info!("Shutdown requested: {:?}", m.reason());
+                    main.shutdown_requested(m.reason()).await;
+                    stream
+                        .write_frame(AndroidAutoControlMessage::ShutdownResponse.into())
+                        .await?;
+                    return Err(super::FrameIoError::PhoneShutdown);
                 }
                 AndroidAutoControlMessage::PingResponse(m) => {
                     let t = m.timestamp();
-                    let delta = std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap()
-                        .as_micros() as i64
-                        - t;
+                    let delta = config.now_micros() - t;
                     main.ping_time_microseconds(delta).await;
                 }
                 AndroidAutoControlMessage::PingRequest(a) => {
@@ -411,7 +481,7 @@ impl ChannelHandlerTrait for ControlChannelHandler {
                         .write_frame(AndroidAutoControlMessage::PingResponse(m).into())
                         .await?;
                 }
-                AndroidAutoControlMessage::AudioFocusResponse(_) => unimplemented!(),
+                AndroidAutoControlMessage::AudioFocusResponse(_) => msg.log_unhandled(),
                 AndroidAutoControlMessage::AudioFocusRequest(m) => {
                     let mut m2 = Wifi::AudioFocusResponse::new();
                     let s = if m.has_audio_focus_type() {
@@ -439,9 +509,17 @@ impl ChannelHandlerTrait for ControlChannelHandler {
                     stream
                         .write_frame(AndroidAutoControlMessage::AudioFocusResponse(m2).into())
                         .await?;
+                    stream.state().set_audio_focus(s);
+                    main.audio_focus_changed(s).await;
                 }
-                AndroidAutoControlMessage::ServiceDiscoveryResponse(_) => unimplemented!(),
-                AndroidAutoControlMessage::ServiceDiscoveryRequest(_m) => {
+                AndroidAutoControlMessage::ServiceDiscoveryResponse(_) => msg.log_unhandled(),
+                AndroidAutoControlMessage::ServiceDiscoveryRequest(m) => {
+                    // A phone can ask again later in the connection. The head unit information and flags are read again,
+                    // the channels cannot change until the next connection because the open channels are known by their ids.
+                    stream.state().update_phone_info(|p| {
+                        p.device_name = Some(m.device_name().to_string());
+                        p.device_brand = Some(m.device_brand().to_string());
+                    });
                     let mut m2 = Wifi::ServiceDiscoveryResponse::new();
                     m2.set_car_model(config.unit.car_model.clone());
                     m2.set_can_play_native_media_during_vr(config.unit.native_media);
@@ -450,7 +528,7 @@ impl ChannelHandlerTrait for ControlChannelHandler {
                     m2.set_head_unit_name(config.unit.name.clone());
                     m2.set_headunit_manufacturer(config.unit.head_manufacturer.clone());
                     m2.set_headunit_model(config.unit.head_model.clone());
-                    if let Some(hide) = config.unit.hide_clock {
+                    if let Some(hide) = main.hide_clock(config.unit.hide_clock) {
                         m2.set_hide_clock(hide);
                     }
                     m2.set_left_hand_drive_vehicle(config.unit.left_hand);
@@ -463,30 +541,103 @@ impl ChannelHandlerTrait for ControlChannelHandler {
                         }
                     }
                     stream
-                        .write_frame(AndroidAutoControlMessage::ServiceDiscoveryResponse(m2).into())
+                        .write_frame(
+                            AndroidAutoControlMessage::ServiceDiscoveryResponse(m2.clone()).into(),
+                        )
                         .await?;
+                    if stream.state().service_discovery_sent(m2) {
+                        log::info!("Answered a repeated service discovery request");
+                    }
                 }
-                AndroidAutoControlMessage::SslAuthComplete(_) => unimplemented!(),
+                AndroidAutoControlMessage::SslAuthComplete(_) => msg.log_unhandled(),
                 AndroidAutoControlMessage::SslHandshake(data) => {
                     stream.do_handshake(data).await?;
                 }
-                AndroidAutoControlMessage::VersionRequest => unimplemented!(),
+                AndroidAutoControlMessage::VersionRequest => msg.log_unhandled(),
                 AndroidAutoControlMessage::VersionResponse {
                     major,
                     minor,
                     status,
                 } => {
-                    if status == 0xFFFF {
-                        log::error!("Version mismatch");
-                        return Err(super::FrameIoError::IncompatibleVersion(major, minor));
+                    match status {
+                        crate::VersionStatus::Mismatch => {
+                            log::error!("Version mismatch");
+                            return Err(super::FrameIoError::IncompatibleVersion(major, minor));
+                        }
+                        crate::VersionStatus::Other(s) => {
+                            log::warn!(
+                                "Android auto client version {}.{} reported status {:#06x}, continuing",
+                                major,
+                                minor,
+                                s
+                            );
+                        }
+                        crate::VersionStatus::Match => {
+                            log::info!("Android auto client version: {}.{}", major, minor);
+                        }
                     }
-                    log::info!("Android auto client version: {}.{}", major, minor);
+                    stream.state().update_phone_info(|p| {
+                        p.version = Some((major, minor));
+                        p.version_status = Some(status);
+                    });
+                    stream
+                        .state()
+                        .set_handshake_quirks(main.handshake_quirks(major, minor));
                     stream.start_handshake().await?;
                 }
             }
         } else {
-            todo!("{:?} {:x?}", msg2.err(), msg);
+            msg.log_parse_errors(&errors);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockMain;
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn channel_open_request_on_control_channel_is_refused() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let frame = channel_open_request(0);
+        assert!(frame.validate_control_bit().is_err());
+        let handler = ControlChannelHandler::new();
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(frame, &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(
+            channel_open_status(&sent.frames()),
+            Wifi::status::Enum::FAIL
+        );
+        assert!(main.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn ping_uses_the_configured_clock() {
+        let main = MockMain::default();
+        let clock = std::sync::Arc::new(crate::ManualClock::new(1_000_000));
+        let config = AndroidAutoConfiguration {
+            clock: Some(clock.clone()),
+            ..MockMain::configuration()
+        };
+        let request: AndroidAutoFrame = AndroidAutoControlMessage::ping_request(&config).into();
+        // the message type, then field 1 holding the timestamp as a varint
+        assert_eq!(request.data, [0x00, 0x0b, 0x08, 0xc0, 0x84, 0x3d]);
+        clock.advance(std::time::Duration::from_micros(2500));
+        let mut m = Wifi::PingResponse::new();
+        m.set_timestamp(1_000_000);
+        let response = incoming(0, false, Wifi::ControlMessage::PING_RESPONSE as u16, &m);
+        let (stream, _sent) = crate::WriteHalf::capturing();
+        ControlChannelHandler::new()
+            .receive_data(response, &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(main.calls(), vec![crate::mock::MockCall::PingTime(2500)]);
+    }
+}
@@ -0,0 +1,101 @@
+//! The tls layer of the android auto protocol, which can be replaced by a user provided backend such as a hardware accelerator
+
+use std::io::{Read, Write};
+
+use crate::{FrameReceiptError, SslError};
+
+/// The result of giving handshake data to a [`CryptoBackend`]
+#[derive(Debug, Default)]
+pub struct HandshakeStep {
+    /// The tls records to send to the compatible android auto device in a handshake message, empty when there is nothing to send
+    pub output: Vec<u8>,
+    /// True once the handshake is complete and frames can be encrypted
+    pub complete: bool,
+}
+
+/// The tls client used to talk to the compatible android auto device.
+/// The head unit is the tls client, the handshake records travel in ssl handshake messages on the control channel,
+/// and afterwards the payload of every encrypted frame is one or more tls application data records.
+/// The default backend is [`rustls::client::ClientConnection`], implement this and return it from
+/// [`crate::AndroidAutoMainTrait::crypto_backend`] to do the tls work elsewhere, such as on a hardware accelerator.
+/// A backend is used for a single connection, by a single task.
+pub trait CryptoBackend: Send {
+    /// Begin the handshake, returning the tls records of the client hello
+    fn start_handshake(&mut self) -> Result<Vec<u8>, SslError>;
+
+    /// Process tls records received from the compatible android auto device during the handshake
+    fn handshake_data(&mut self, data: &[u8]) -> Result<HandshakeStep, SslError>;
+
+    /// Encrypt the payload of a frame, returning the tls records to send in its place
+    fn encrypt(&mut self, plain: &[u8]) -> Result<Vec<u8>, SslError>;
+
+    /// Decrypt the payload of an encrypted frame, returning the plaintext.
+    /// Backends other than rustls report their own failures with [`FrameReceiptError::Backend`].
+    fn decrypt(&mut self, records: &[u8]) -> Result<Vec<u8>, FrameReceiptError>;
+}
+
+impl CryptoBackend for rustls::client::ClientConnection {
+    fn start_handshake(&mut self) -> Result<Vec<u8>, SslError> {
+        let mut buf = Vec::new();
+        self.write_tls(&mut buf).map_err(SslError::Tls)?;
+        Ok(buf)
+    }
+
+    fn handshake_data(&mut self, data: &[u8]) -> Result<HandshakeStep, SslError> {
+        let mut dc = std::io::Cursor::new(data);
+        self.read_tls(&mut dc).map_err(SslError::Tls)?;
+        let state = self.process_new_packets().map_err(SslError::Processing)?;
+        if state.peer_has_closed() {
+            return Err(SslError::PeerClosed);
+        }
+        let mut output = Vec::new();
+        if self.wants_write() {
+            self.write_tls(&mut output).map_err(SslError::Tls)?;
+        }
+        Ok(HandshakeStep {
+            output,
+            complete: !self.is_handshaking(),
+        })
+    }
+
+    fn encrypt(&mut self, plain: &[u8]) -> Result<Vec<u8>, SslError> {
+        let mut data = Vec::new();
+        self.writer().write_all(plain).map_err(SslError::Write)?;
+        self.write_tls(&mut data).map_err(SslError::Tls)?;
+        Ok(data)
+    }
+
+    fn decrypt(&mut self, records: &[u8]) -> Result<Vec<u8>, FrameReceiptError> {
+        let mut plain_data = vec![0u8; records.len()];
+        let mut cursor = std::io::Cursor::new(records);
+        let mut index = 0;
+        loop {
+            let n = self
+                .read_tls(&mut cursor)
+                .map_err(FrameReceiptError::TlsReadError)?;
+            if n == 0 {
+                break;
+            }
+            let pnp = self
+                .process_new_packets()
+                .map_err(FrameReceiptError::TlsProcessingError)?;
+
+            loop {
+                let amount = pnp.plaintext_bytes_to_read();
+                if amount > 0 {
+                    match self.reader().read(&mut plain_data[index..]) {
+                        Ok(0) => break, // EOF for now
+                        Ok(n) => index += n,
+                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                        Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                        Err(e) => return Err(FrameReceiptError::TlsReadError(e)),
+                    }
+                } else {
+                    break;
+                }
+            }
+        }
+        plain_data.truncate(index);
+        Ok(plain_data)
+    }
+}
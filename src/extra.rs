@@ -0,0 +1,74 @@
+//! The handler for extra channels contributed by the user, for experimenting with channels this crate does not implement
+
+use crate::{
+    AndroidAutoConfiguration, AndroidAutoFrame, AndroidAutoMainTrait, ChannelHandlerTrait,
+    ChannelId, Wifi, common::AndroidAutoCommonMessage,
+};
+
+/// The handler for a channel from [`AndroidAutoMainTrait::extra_channels`]
+pub struct ExtraChannelHandler {
+    /// The index of the channel in the list given by the user
+    index: usize,
+    /// The descriptor given by the user
+    descriptor: Wifi::ChannelDescriptor,
+}
+
+impl ExtraChannelHandler {
+    /// Construct a handler for the descriptor at the specified index of the list given by the user
+    pub fn new(index: usize, descriptor: Wifi::ChannelDescriptor) -> Self {
+        Self { index, descriptor }
+    }
+
+    /// The index of the channel in the list given by the user
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl ChannelHandlerTrait for ExtraChannelHandler {
+    fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        _config: &AndroidAutoConfiguration,
+        chanid: ChannelId,
+        _main: &T,
+    ) -> Option<Wifi::ChannelDescriptor> {
+        let mut chan = self.descriptor.clone();
+        if chan.has_channel_id() && chan.channel_id() != chanid as u32 {
+            log::error!(
+                "Extra channel {} asked for channel id {}, which is not free, it would be {}",
+                self.index,
+                chan.channel_id(),
+                chanid
+            );
+        } else {
+            chan.set_channel_id(chanid as u32);
+        }
+        Some(chan)
+    }
+
+    async fn receive_data<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        msg: AndroidAutoFrame,
+        stream: &crate::WriteHalf,
+        _config: &AndroidAutoConfiguration,
+        main: &T,
+    ) -> Result<(), super::FrameIoError> {
+        let channel = msg.header.channel_id;
+        let mut errors = Vec::new();
+        if let Some(AndroidAutoCommonMessage::ChannelOpenRequest(m)) =
+            msg.parse::<AndroidAutoCommonMessage>(&mut errors)
+        {
+            super::respond_channel_open(
+                crate::ChannelKind::Extra,
+                channel,
+                &m,
+                async { true },
+                stream,
+            )
+            .await?;
+            return Ok(());
+        }
+        main.receive_extra_frame(self.index, msg).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,252 @@
+//! Reassembly of the h264 video from the compatible android auto device into complete access units
+
+/// The start code placed in front of every nal unit given out by [`H264Depacketizer`]
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// An access unit delimiter nal unit, with a primary picture type allowing any kind of slice
+const ACCESS_UNIT_DELIMITER: [u8; 2] = [0x09, 0xf0];
+
+/// The nal unit type of an access unit delimiter
+const NAL_ACCESS_UNIT_DELIMITER: u8 = 9;
+
+/// The positions of the three byte start codes in annex b h264 video, looking from the specified position onwards
+fn start_codes(data: &[u8], from: usize) -> Vec<usize> {
+    let mut starts = Vec::new();
+    let mut i = from;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    starts
+}
+
+/// Remove the trailing zero bytes of a nal unit that is known to be complete, they belong to the following start code
+fn trim_zeros(nal: &[u8]) -> &[u8] {
+    &nal[..nal.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1)]
+}
+
+/// Reassembles the h264 video from the compatible android auto device into complete access units (one picture each).
+/// Phones differ in how they split video across media indications, some send one nal unit at a time, some one slice at a time,
+/// and some several nal units at once. This gives out each access unit as a single chunk, with every nal unit preceded by a four byte start code.
+/// Access unit delimiters in the stream mark the start of an access unit, they are either removed or put at the start of every access unit.
+/// A nal unit, or its start code, may be split across chunks, the end of one is only known once the next start code arrives.
+///
+/// The end of an access unit is only known once the next one starts, so each access unit is given out when the start of the first nal unit of the next one arrives,
+/// or by [`Self::flush`]. Select it for a connection with [`crate::VideoPacketization`], or use it directly on the chunks given to
+/// [`crate::AndroidAutoVideoChannelTrait::receive_video`].
+#[derive(Debug, Default)]
+pub struct H264Depacketizer {
+    /// True to start every access unit with an access unit delimiter, false to remove them
+    delimiters: bool,
+    /// The nal units of the access unit being assembled, without start codes
+    nals: Vec<Vec<u8>>,
+    /// True once the access unit being assembled has a slice of a picture
+    has_slice: bool,
+    /// The timestamp of the chunk that started the access unit being assembled
+    timestamp: Option<u64>,
+    /// The received bytes that are not part of a complete nal unit yet.
+    /// After a start code this is the nal unit it starts, without the start code. Before the first one it is at most the last two bytes received,
+    /// which may be the start of a start code.
+    partial: Vec<u8>,
+    /// True once a start code has been received, so that [`Self::partial`] is a nal unit
+    in_nal: bool,
+    /// True once the nal unit in [`Self::partial`] has been checked for starting a new access unit
+    partial_checked: bool,
+    /// The timestamp of the chunk that started the nal unit in [`Self::partial`]
+    partial_timestamp: Option<u64>,
+}
+
+impl H264Depacketizer {
+    /// Construct a depacketizer, specifying whether every access unit should start with an access unit delimiter
+    pub fn new(delimiters: bool) -> Self {
+        Self {
+            delimiters,
+            ..Default::default()
+        }
+    }
+
+    /// Returns true when the nal unit is the first of a new access unit
+    fn starts_access_unit(&self, nal: &[u8]) -> bool {
+        match nal[0] & 0x1f {
+            NAL_ACCESS_UNIT_DELIMITER => !self.nals.is_empty(),
+            // sei, sps, pps and the reserved types that can only precede the slices of a picture
+            6..=8 | 14..=18 => self.has_slice,
+            // a slice, whose first_mb_in_slice is zero (a single 1 bit in exp-golomb coding)
+            1 | 5 => self.has_slice && nal.get(1).is_some_and(|b| b & 0x80 != 0),
+            _ => false,
+        }
+    }
+
+    /// Take the access unit being assembled, if there is one
+    fn take(&mut self) -> Option<(Vec<u8>, Option<u64>)> {
+        if self.nals.is_empty() {
+            return None;
+        }
+        let nals = std::mem::take(&mut self.nals);
+        self.has_slice = false;
+        let mut au = Vec::with_capacity(
+            nals.iter()
+                .map(|n| n.len() + START_CODE.len())
+                .sum::<usize>()
+                + START_CODE.len()
+                + ACCESS_UNIT_DELIMITER.len(),
+        );
+        if self.delimiters {
+            au.extend_from_slice(&START_CODE);
+            au.extend_from_slice(&ACCESS_UNIT_DELIMITER);
+        }
+        for nal in nals
+            .iter()
+            .filter(|n| n[0] & 0x1f != NAL_ACCESS_UNIT_DELIMITER)
+        {
+            au.extend_from_slice(&START_CODE);
+            au.extend_from_slice(nal);
+        }
+        Some((au, self.timestamp.take()))
+    }
+
+    /// Add a complete nal unit to the access unit being assembled, returning that access unit first when the nal unit starts a new one.
+    /// Checked is true when the start of the nal unit was already checked for that.
+    fn add_nal(
+        &mut self,
+        nal: &[u8],
+        checked: bool,
+        timestamp: Option<u64>,
+    ) -> Option<(Vec<u8>, Option<u64>)> {
+        if nal.is_empty() {
+            return None;
+        }
+        let done = if !checked && self.starts_access_unit(nal) {
+            self.take()
+        } else {
+            None
+        };
+        if self.nals.is_empty() {
+            self.timestamp = timestamp;
+        }
+        if matches!(nal[0] & 0x1f, 1 | 5) {
+            self.has_slice = true;
+        }
+        self.nals.push(nal.to_vec());
+        done
+    }
+
+    /// Add a chunk of video as received from the compatible android auto device, returning the access units it completes with their timestamps
+    pub fn push(&mut self, data: &[u8], timestamp: Option<u64>) -> Vec<(Vec<u8>, Option<u64>)> {
+        let mut out = Vec::new();
+        let mut buf = std::mem::take(&mut self.partial);
+        // a start code split across chunks begins in the last two bytes of the previous one
+        let from = buf.len().saturating_sub(2);
+        buf.extend_from_slice(data);
+        let mut begin = 0;
+        for start in start_codes(&buf, from) {
+            let nal = trim_zeros(&buf[begin..start]);
+            if self.in_nal {
+                out.extend(self.add_nal(nal, self.partial_checked, self.partial_timestamp));
+            } else if !nal.is_empty() {
+                log::warn!("Dropping {} bytes of video before a start code", nal.len());
+            }
+            self.in_nal = true;
+            self.partial_checked = false;
+            self.partial_timestamp = timestamp;
+            begin = start + 3;
+        }
+        if !self.in_nal {
+            begin = begin.max(buf.len().saturating_sub(2));
+            let dropped = trim_zeros(&buf[..begin]);
+            if !dropped.is_empty() {
+                log::warn!(
+                    "Dropping {} bytes of video before a start code",
+                    dropped.len()
+                );
+            }
+        }
+        buf.drain(..begin);
+        self.partial = buf;
+        if self.in_nal && !self.partial_checked && self.partial.len() >= 2 {
+            self.partial_checked = true;
+            if self.starts_access_unit(&self.partial) {
+                out.extend(self.take());
+            }
+        }
+        out
+    }
+
+    /// Give out the access unit being assembled, for the end of the video stream
+    pub fn flush(&mut self) -> Option<(Vec<u8>, Option<u64>)> {
+        let partial = std::mem::take(&mut self.partial);
+        if std::mem::take(&mut self.in_nal) {
+            // the stream ends here, so the last nal unit belongs to the access unit being assembled
+            self.add_nal(trim_zeros(&partial), true, self.partial_timestamp);
+        }
+        self.take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two access units, the first with a sequence and picture parameter set, with zero bytes inside the slices
+    const STREAM: &[u8] = &[
+        0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88, 0x11, 0, 0, 0x22,
+        0, 0, 0, 1, 0x41, 0x9a, 0x33, 0,
+    ];
+
+    /// Push the chunks and flush, returning every access unit given out
+    fn depacketize(chunks: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut d = H264Depacketizer::new(false);
+        let mut out: Vec<_> = chunks
+            .iter()
+            .flat_map(|c| d.push(c, None))
+            .map(|(au, _)| au)
+            .collect();
+        out.extend(d.flush().map(|(au, _)| au));
+        out
+    }
+
+    #[test]
+    fn whole_stream_gives_the_access_units() {
+        assert_eq!(
+            depacketize(&[STREAM]),
+            vec![
+                vec![
+                    0, 0, 0, 1, 0x67, 0x42, 0, 0, 0, 1, 0x68, 0xce, 0, 0, 0, 1, 0x65, 0x88, 0x11,
+                    0, 0, 0x22
+                ],
+                vec![0, 0, 0, 1, 0x41, 0x9a, 0x33],
+            ]
+        );
+    }
+
+    #[test]
+    fn every_split_gives_the_same_access_units() {
+        let whole = depacketize(&[STREAM]);
+        for i in 0..=STREAM.len() {
+            let (a, b) = STREAM.split_at(i);
+            assert_eq!(depacketize(&[a, b]), whole, "split at {}", i);
+        }
+    }
+
+    #[test]
+    fn zeros_at_the_end_of_a_chunk_are_kept_inside_a_nal_unit() {
+        let au = depacketize(&[&[0, 0, 0, 1, 0x65, 0x88, 0x11, 0], &[0, 0x22]]);
+        assert_eq!(au, vec![vec![0, 0, 0, 1, 0x65, 0x88, 0x11, 0, 0, 0x22]]);
+    }
+
+    #[test]
+    fn access_unit_is_given_out_when_the_next_one_starts() {
+        let mut d = H264Depacketizer::new(false);
+        assert!(
+            d.push(&[0, 0, 0, 1, 0x65, 0x88, 0x11, 0, 0], Some(1))
+                .is_empty()
+        );
+        let out = d.push(&[1, 0x41, 0x9a], Some(2));
+        assert_eq!(out, vec![(vec![0, 0, 0, 1, 0x65, 0x88, 0x11], Some(1))]);
+        assert_eq!(d.flush(), Some((vec![0, 0, 0, 1, 0x41, 0x9a], Some(2))));
+    }
+}
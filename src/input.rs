@@ -4,13 +4,13 @@ use protobuf::Message;
 
 use crate::{
     AndroidAutoConfiguration, AndroidAutoFrame, AndroidAutoMainTrait, ChannelHandlerTrait,
-    ChannelId, FrameHeader, FrameHeaderType, StreamMux, Wifi, common::AndroidAutoCommonMessage,
+    ChannelId, FrameHeader, StreamMux, Wifi, common::AndroidAutoCommonMessage,
     frame_header::FrameHeaderContents,
 };
 
 /// A message about binding input buttons on a compatible android auto head unit
 #[derive(Debug)]
-enum InputMessage {
+pub(crate) enum InputMessage {
     /// A message requesting input buttons to be bound
     BindingRequest(ChannelId, Wifi::BindingRequest),
     /// A message that responds to a binding request, indicating success or failure of the request
@@ -34,7 +34,7 @@ impl From<InputMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -50,7 +50,7 @@ impl From<InputMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -63,9 +63,7 @@ impl TryFrom<&AndroidAutoFrame> for InputMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
         use protobuf::Enum;
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if let Some(sys) = Wifi::input_channel_message::Enum::from_i32(ty as i32) {
             match sys {
                 Wifi::input_channel_message::Enum::BINDING_REQUEST => {
@@ -75,9 +73,15 @@ impl TryFrom<&AndroidAutoFrame> for InputMessage {
                         Err(e) => Err(format!("Invalid input bind request: {}", e)),
                     }
                 }
-                Wifi::input_channel_message::Enum::BINDING_RESPONSE => unimplemented!(),
-                Wifi::input_channel_message::Enum::INPUT_EVENT_INDICATION => todo!(),
-                Wifi::input_channel_message::Enum::NONE => todo!(),
+                Wifi::input_channel_message::Enum::BINDING_RESPONSE => {
+                    Err("Unsupported message BINDING_RESPONSE".to_string())
+                }
+                Wifi::input_channel_message::Enum::INPUT_EVENT_INDICATION => {
+                    Err("Unsupported message INPUT_EVENT_INDICATION".to_string())
+                }
+                Wifi::input_channel_message::Enum::NONE => {
+                    Err("Unsupported message NONE".to_string())
+                }
             }
         } else {
             Err(format!("Not converted message: {:x?}", value.data))
@@ -85,6 +89,15 @@ impl TryFrom<&AndroidAutoFrame> for InputMessage {
     }
 }
 
+/// The reason binding a single keycode failed
+#[derive(Clone, Copy, Debug)]
+enum BindingFailure {
+    /// The keycode is not in [`crate::InputConfiguration::keycodes`]
+    Unsupported,
+    /// [`crate::AndroidAutoInputChannelTrait::binding_request`] returned an error
+    Refused,
+}
+
 /// The handler for the input channel for the android auto protocol
 pub struct InputChannelHandler {}
 
@@ -124,22 +137,41 @@ impl ChannelHandlerTrait for InputChannelHandler {
         main: &T,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<InputMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<InputMessage>(&mut errors) {
             match msg2 {
                 InputMessage::BindingRequest(chan, m) => {
-                    let mut status = true;
                     let ics = main.retrieve_input_configuration();
-                    for c in &m.scan_codes {
-                        if !ics.keycodes.contains(&(*c as u32)) {
-                            status = false;
-                            break;
-                        }
-                        if main.binding_request(*c as u32).await.is_err() {
-                            status = false;
-                            break;
+                    let codes: Vec<u32> = m.scan_codes.iter().map(|c| *c as u32).collect();
+                    let unsupported: Vec<String> = codes
+                        .iter()
+                        .filter(|c| !ics.keycodes.contains(c))
+                        .map(|c| format!("{} ({:?})", c, BindingFailure::Unsupported))
+                        .collect();
+                    let status = if !unsupported.is_empty() {
+                        log::warn!("Binding failed for keycodes: {}", unsupported.join(", "));
+                        false
+                    } else {
+                        let mut bound = Vec::new();
+                        let mut status = true;
+                        for c in codes {
+                            if main.binding_request(c).await.is_err() {
+                                log::warn!(
+                                    "Binding failed for keycode {} ({:?}), releasing {:?}",
+                                    c,
+                                    BindingFailure::Refused,
+                                    bound
+                                );
+                                for b in bound.iter().rev() {
+                                    main.binding_release(*b).await;
+                                }
+                                status = false;
+                                break;
+                            }
+                            bound.push(c);
                         }
-                    }
+                        status
+                    };
                     let mut m2 = Wifi::BindingResponse::new();
                     m2.set_status(if status {
                         Wifi::status::Enum::OK
@@ -150,27 +182,75 @@ impl ChannelHandlerTrait for InputChannelHandler {
                         .write_frame(InputMessage::BindingResponse(chan, m2).into())
                         .await?;
                 }
-                InputMessage::BindingResponse(_, _) => unimplemented!(),
-                InputMessage::InputEvent(_, _) => unimplemented!(),
+                InputMessage::BindingResponse(_, _) => msg.log_unhandled(),
+                InputMessage::InputEvent(_, _) => msg.log_unhandled(),
             }
             return Ok(());
         }
-        let msg2: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::Input,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        todo!();
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockBehavior, MockCall, MockMain};
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn failed_binding_releases_the_bound_keycodes() {
+        let main = MockMain::default()
+            .with_input(crate::InputConfiguration {
+                keycodes: vec![1, 2, 3],
+                touchscreen: None,
+            })
+            .with_behavior(MockBehavior {
+                failing_bindings: [2].into(),
+                ..Default::default()
+            });
+        let config = MockMain::configuration();
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        let mut m = Wifi::BindingRequest::new();
+        m.scan_codes = vec![1, 2, 3];
+        let frame = incoming(
+            1,
+            false,
+            Wifi::input_channel_message::Enum::BINDING_REQUEST as u16,
+            &m,
+        );
+        InputChannelHandler {}
+            .receive_data(frame, &stream, &config, &main)
+            .await
+            .unwrap();
+        let response: Wifi::BindingResponse = response(
+            &sent.frames(),
+            Wifi::input_channel_message::Enum::BINDING_RESPONSE as u16,
+        );
+        assert_eq!(response.status(), Wifi::status::Enum::FAIL);
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::BindingRequest(1),
+                MockCall::BindingRequest(2),
+                MockCall::BindingRelease(1)
+            ]
+        );
     }
 }
@@ -7,34 +7,23 @@ use crate::{
     ChannelId, StreamMux, Wifi, common::AndroidAutoCommonMessage,
 };
 
-/// A message about the media status of currently playing media
+/// A message about the media status of currently playing media.
+/// Both messages on the media status channel only travel from the phone to the head unit, so there is no conversion into a frame.
 #[derive(Debug)]
-enum MediaStatusMessage {
-    /// A message containing basic information about changes to the currently playing media
+pub(crate) enum MediaStatusMessage {
+    /// A message containing basic information about changes to the currently playing media, sent by the phone
     Playback(ChannelId, Wifi::MediaInfoChannelPlaybackData),
-    /// The metadata containing information about the media currently playing
+    /// The metadata containing information about the media currently playing, sent by the phone
     Metadata(ChannelId, Wifi::MediaInfoChannelMetadataData),
     /// The media status message was invalid for some reason
     Invalid,
 }
 
-impl From<MediaStatusMessage> for AndroidAutoFrame {
-    fn from(value: MediaStatusMessage) -> Self {
-        match value {
-            MediaStatusMessage::Playback(_, _) => todo!(),
-            MediaStatusMessage::Metadata(_, _) => todo!(),
-            MediaStatusMessage::Invalid => unimplemented!(),
-        }
-    }
-}
-
 impl TryFrom<&AndroidAutoFrame> for MediaStatusMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
         use protobuf::Enum;
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if let Some(sys) = Wifi::media_info_channel_message::Enum::from_i32(ty as i32) {
             match sys {
                 Wifi::media_info_channel_message::Enum::PLAYBACK => {
@@ -51,7 +40,9 @@ impl TryFrom<&AndroidAutoFrame> for MediaStatusMessage {
                         Err(_) => Ok(Self::Invalid),
                     }
                 }
-                Wifi::media_info_channel_message::Enum::NONE => todo!(),
+                Wifi::media_info_channel_message::Enum::NONE => {
+                    Err("Unsupported message NONE".to_string())
+                }
             }
         } else {
             Err(format!("Not converted message: {:x?}", value.data))
@@ -87,8 +78,8 @@ impl ChannelHandlerTrait for MediaStatusChannelHandler {
         _main: &T,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<MediaStatusMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<MediaStatusMessage>(&mut errors) {
             match msg2 {
                 MediaStatusMessage::Metadata(_, m) => {
                     log::info!("Metadata {:?}", m);
@@ -105,19 +96,21 @@ impl ChannelHandlerTrait for MediaStatusChannelHandler {
         let msg3: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
         if let Ok(msg2) = msg3 {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::MediaStatus,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        todo!("{:?} {:?}", msg2, msg3);
+        msg.log_parse_errors(&errors);
+        Ok(())
     }
 }
@@ -0,0 +1,105 @@
+//! The association of android auto channels to the message types they carry, for tooling such as decoders and fuzzers
+
+use crate::Wifi;
+
+/// The kinds of channel that this crate implements
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelKind {
+    /// The control channel, always channel 0
+    Control,
+    /// The input channel
+    Input,
+    /// The sensor channel
+    Sensor,
+    /// The video channel
+    Video,
+    /// The media audio channel
+    MediaAudio,
+    /// The speech audio channel
+    SpeechAudio,
+    /// The system audio channel
+    SystemAudio,
+    /// The audio input (microphone) channel
+    AvInput,
+    /// The bluetooth channel
+    Bluetooth,
+    /// The navigation channel
+    Navigation,
+    /// The media status channel
+    MediaStatus,
+    /// A channel added by the user with the `unstable` feature, which this crate does not implement
+    Extra,
+}
+
+/// A message type that can be carried on a channel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MessageType {
+    /// The name of the message type, as defined in the protobuf enum
+    pub name: String,
+    /// The value of the first two bytes of a frame carrying this message type
+    pub id: u16,
+    /// The value of the control bit in the frame header for this message type
+    pub control: bool,
+}
+
+/// Build the list of message types from a protobuf enum
+fn message_types<E: protobuf::EnumFull>(control: bool) -> Vec<MessageType> {
+    E::VALUES
+        .iter()
+        .map(|v| MessageType {
+            name: v.descriptor().name().to_string(),
+            id: v.value() as u16,
+            control,
+        })
+        .collect()
+}
+
+impl ChannelKind {
+    /// All of the kinds of channel
+    pub const ALL: [ChannelKind; 12] = [
+        ChannelKind::Control,
+        ChannelKind::Input,
+        ChannelKind::Sensor,
+        ChannelKind::Video,
+        ChannelKind::MediaAudio,
+        ChannelKind::SpeechAudio,
+        ChannelKind::SystemAudio,
+        ChannelKind::AvInput,
+        ChannelKind::Bluetooth,
+        ChannelKind::Navigation,
+        ChannelKind::MediaStatus,
+        ChannelKind::Extra,
+    ];
+
+    /// The message types carried by this kind of channel, including the messages common to all channels other than the control channel.
+    /// Only the common messages are known for an extra channel.
+    pub fn message_types(&self) -> Vec<MessageType> {
+        let mut types = match self {
+            ChannelKind::Control => return message_types::<Wifi::ControlMessage>(false),
+            ChannelKind::Input => message_types::<Wifi::input_channel_message::Enum>(false),
+            ChannelKind::Sensor => message_types::<Wifi::sensor_channel_message::Enum>(false),
+            ChannelKind::Video
+            | ChannelKind::MediaAudio
+            | ChannelKind::SpeechAudio
+            | ChannelKind::SystemAudio
+            | ChannelKind::AvInput => message_types::<Wifi::avchannel_message::Enum>(false),
+            ChannelKind::Bluetooth => message_types::<Wifi::bluetooth_channel_message::Enum>(false),
+            ChannelKind::Navigation => {
+                message_types::<Wifi::navigation_channel_message::Enum>(false)
+            }
+            ChannelKind::MediaStatus => {
+                message_types::<Wifi::media_info_channel_message::Enum>(false)
+            }
+            ChannelKind::Extra => Vec::new(),
+        };
+        types.append(&mut message_types::<Wifi::CommonMessage>(true));
+        types
+    }
+
+    /// Find the message type for a frame on this kind of channel, given the first two bytes of the frame and the control bit of its header
+    pub fn decode(&self, id: u16, control: bool) -> Option<MessageType> {
+        self.message_types()
+            .into_iter()
+            .find(|t| t.id == id && t.control == control)
+    }
+}
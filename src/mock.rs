@@ -0,0 +1,579 @@
+//! A mock head unit, for testing code that uses this crate without real hardware
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use super::*;
+
+/// The results that the mock head unit gives for the trait methods that can fail
+#[derive(Clone, Debug)]
+pub struct MockBehavior {
+    /// The result of opening an audio output or audio input channel
+    pub open_channel: Result<(), ChannelOpenError>,
+    /// The number of audio channel opens and video setups, counted together, that fail with [`ChannelOpenError::Transient`]
+    /// before [`Self::open_channel`] or [`Self::setup_video`] is given
+    pub transient_open_failures: usize,
+    /// The result of setting up the video stream
+    pub setup_video: Result<(), ChannelOpenError>,
+    /// The keycodes whose binding requests fail, every other keycode binds
+    pub failing_bindings: HashSet<u32>,
+    /// The result of starting a sensor
+    pub start_sensor: Result<(), ()>,
+    /// Panic with this message when video or audio data is received
+    pub panic_on_media: Option<&'static str>,
+    /// The answer to a new connection
+    pub accept_connection: Result<(), Option<String>>,
+}
+
+impl Default for MockBehavior {
+    fn default() -> Self {
+        Self {
+            open_channel: Ok(()),
+            transient_open_failures: 0,
+            setup_video: Ok(()),
+            failing_bindings: HashSet::new(),
+            start_sensor: Ok(()),
+            panic_on_media: None,
+            accept_connection: Ok(()),
+        }
+    }
+}
+
+/// The optional parts of the protocol that the mock head unit supports
+#[derive(Clone, Debug)]
+pub struct MockSupport {
+    /// Advertise bluetooth
+    pub bluetooth: bool,
+    /// Advertise navigation
+    pub navigation: bool,
+    /// Advertise a secondary video stream, its video is recorded with the primary video
+    pub secondary_video: bool,
+    /// Advertise the media status channel
+    pub media_status: bool,
+    /// The audio output channels advertised
+    pub output_channels: Vec<AudioChannelType>,
+    /// The formats advertised for every audio output channel after its default format
+    pub extra_output_formats: Vec<AudioFormat>,
+    /// The extra channels advertised
+    #[cfg(feature = "unstable")]
+    pub extra_channels: Vec<Wifi::ChannelDescriptor>,
+    /// The bluetooth address of the phone to reach out to at the start, the connection attempts always fail
+    #[cfg(feature = "wireless")]
+    pub last_bluetooth_phone: Option<String>,
+}
+
+impl Default for MockSupport {
+    fn default() -> Self {
+        Self {
+            bluetooth: false,
+            navigation: false,
+            secondary_video: false,
+            media_status: true,
+            output_channels: AudioChannelType::ALL.to_vec(),
+            extra_output_formats: Vec::new(),
+            #[cfg(feature = "unstable")]
+            extra_channels: Vec::new(),
+            #[cfg(feature = "wireless")]
+            last_bluetooth_phone: None,
+        }
+    }
+}
+
+/// A record of a single call made on the mock head unit
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockCall {
+    /// The android auto device connected
+    Connect,
+    /// The android auto device disconnected
+    Disconnect,
+    /// An audio output channel was opened
+    OpenOutputChannel(AudioChannelType),
+    /// An audio output channel was closed
+    CloseOutputChannel(AudioChannelType),
+    /// The format of an audio output channel was chosen
+    OutputAudioFormat(AudioChannelType, AudioFormat),
+    /// An audio output channel was started
+    StartOutputAudio(AudioChannelType),
+    /// An audio output channel was stopped
+    StopOutputAudio(AudioChannelType),
+    /// The audio input channel was opened
+    OpenInputChannel,
+    /// The audio input channel was closed
+    CloseInputChannel,
+    /// The audio input channel was started
+    StartInputAudio,
+    /// The audio input channel was stopped
+    StopInputAudio,
+    /// An ack for audio input data was received
+    AudioInputAck(u8),
+    /// The layout of the video stream was reported
+    VideoLayout(VideoLayout),
+    /// The video stream was setup
+    SetupVideo,
+    /// The video stream was torn down
+    TeardownVideo,
+    /// The video focus was set
+    SetFocus(bool),
+    /// A sequence error in the video was reported
+    VideoSequenceError(FrameSequenceError),
+    /// A keycode binding was requested
+    BindingRequest(u32),
+    /// A keycode binding was released
+    BindingRelease(u32),
+    /// A sensor was started
+    StartSensor(Wifi::sensor_type::Enum),
+    /// A ping time was reported
+    PingTime(i64),
+    /// The android auto device requested a shutdown for the reason
+    ShutdownRequested(Wifi::shutdown_reason::Enum),
+    /// A navigation session started
+    NavigationStarted,
+    /// A navigation status was received
+    NavigationStatus(Wifi::navigation_status::Enum),
+    /// A navigation turn event was received
+    TurnIndication,
+    /// A navigation session ended
+    NavigationStopped,
+    /// A connection to the phone with the bluetooth address was attempted
+    ConnectBluetooth(String),
+}
+
+/// A head unit that implements [`AndroidAutoMainTrait`] with configurable results and records everything that happens to it
+pub struct MockMain {
+    /// The video configuration reported to the android auto device
+    video: VideoConfiguration,
+    /// The input configuration reported to the android auto device
+    input: InputConfiguration,
+    /// The sensors reported to the android auto device
+    sensors: SensorInformation,
+    /// The results for the trait methods that can fail
+    behavior: MockBehavior,
+    /// The optional parts of the protocol that are supported
+    support: MockSupport,
+    /// The bluetooth configuration, used when bluetooth is supported
+    bluetooth: BluetoothInformation,
+    /// The sender for messages to the android auto device
+    sender: tokio::sync::mpsc::Sender<SendableAndroidAutoMessage>,
+    /// The receiver handed out to the crate by [`AndroidAutoMainTrait::get_receiver`]
+    receiver: Mutex<Option<tokio::sync::mpsc::Receiver<SendableAndroidAutoMessage>>>,
+    /// The calls made, in order
+    calls: Mutex<Vec<MockCall>>,
+    /// The video data received, with the timestamp
+    video_data: Mutex<Vec<(bytes::Bytes, Option<u64>)>>,
+    /// The audio data received, with the channel it was received on
+    audio_data: Mutex<Vec<(AudioChannelType, Vec<u8>)>>,
+}
+
+impl Default for MockMain {
+    fn default() -> Self {
+        Self::new(
+            VideoConfiguration {
+                resolution: Wifi::video_resolution::Enum::_480p,
+                fps: Wifi::video_fps::Enum::_30,
+                dpi: 111,
+                margin_width: 0,
+                margin_height: 0,
+                frame_drop: Default::default(),
+                packetization: Default::default(),
+                orientation: Default::default(),
+            },
+            InputConfiguration {
+                keycodes: Vec::new(),
+                touchscreen: Some((800, 480)),
+            },
+            SensorInformation {
+                sensors: HashSet::new(),
+                min_intervals: HashMap::new(),
+            },
+        )
+    }
+}
+
+impl MockMain {
+    /// Create a mock head unit with the given configuration
+    pub fn new(
+        video: VideoConfiguration,
+        input: InputConfiguration,
+        sensors: SensorInformation,
+    ) -> Self {
+        let (sender, receiver) = tokio::sync::mpsc::channel(50);
+        Self {
+            video,
+            input,
+            sensors,
+            behavior: MockBehavior::default(),
+            support: MockSupport::default(),
+            bluetooth: BluetoothInformation {
+                address: "00:00:00:00:00:00".to_string(),
+            },
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            calls: Mutex::new(Vec::new()),
+            video_data: Mutex::new(Vec::new()),
+            audio_data: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// A configuration for running the mock head unit, with the built in certificate
+    pub fn configuration() -> AndroidAutoConfiguration {
+        AndroidAutoConfiguration {
+            unit: HeadUnitInfo {
+                name: "Mock".to_string(),
+                car_model: "Mock".to_string(),
+                car_year: "2024".to_string(),
+                car_serial: "0".to_string(),
+                left_hand: true,
+                head_manufacturer: "Mock".to_string(),
+                head_model: "Mock".to_string(),
+                sw_build: "1".to_string(),
+                sw_version: "1".to_string(),
+                native_media: false,
+                hide_clock: None,
+            },
+            custom_certificate: None,
+            extra_roots: Vec::new(),
+            recorder: None,
+            idle_timeout: None,
+            handshake_timeout: None,
+            bluetooth_profile: Default::default(),
+            clock: None,
+            overflow: Default::default(),
+            ping_only_when_idle: false,
+            max_frame_data_size: None,
+        }
+    }
+
+    /// Set the results for the trait methods that can fail
+    pub fn with_behavior(mut self, behavior: MockBehavior) -> Self {
+        self.behavior = behavior;
+        self
+    }
+
+    /// Set the input configuration reported to the android auto device
+    pub fn with_input(mut self, input: InputConfiguration) -> Self {
+        self.input = input;
+        self
+    }
+
+    /// Set the sensors reported to the android auto device
+    pub fn with_sensors(mut self, sensors: SensorInformation) -> Self {
+        self.sensors = sensors;
+        self
+    }
+
+    /// Set the optional parts of the protocol that are supported
+    pub fn with_support(mut self, support: MockSupport) -> Self {
+        self.support = support;
+        self
+    }
+
+    /// Retrieve a sender for messages to the android auto device
+    pub fn sender(&self) -> tokio::sync::mpsc::Sender<SendableAndroidAutoMessage> {
+        self.sender.clone()
+    }
+
+    /// Retrieve the calls made so far, in order
+    pub fn calls(&self) -> Vec<MockCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Retrieve the video data received so far, with the timestamp of each chunk
+    pub fn video_data(&self) -> Vec<(bytes::Bytes, Option<u64>)> {
+        self.video_data.lock().unwrap().clone()
+    }
+
+    /// Take the video data received so far, with the timestamp of each chunk, so that only later video is kept
+    pub fn take_video_data(&self) -> Vec<(bytes::Bytes, Option<u64>)> {
+        std::mem::take(&mut *self.video_data.lock().unwrap())
+    }
+
+    /// Retrieve the audio data received so far, with the channel of each chunk
+    pub fn audio_data(&self) -> Vec<(AudioChannelType, Vec<u8>)> {
+        self.audio_data.lock().unwrap().clone()
+    }
+
+    /// Record a call
+    fn record(&self, call: MockCall) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    /// The result of the audio channel open or video setup that was just recorded, the specified result once the transient failures are used up
+    fn open_result(&self, result: Result<(), ChannelOpenError>) -> Result<(), ChannelOpenError> {
+        let opens = self
+            .calls
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| {
+                matches!(
+                    c,
+                    MockCall::OpenOutputChannel(_)
+                        | MockCall::OpenInputChannel
+                        | MockCall::SetupVideo
+                )
+            })
+            .count();
+        if opens <= self.behavior.transient_open_failures {
+            Err(ChannelOpenError::Transient)
+        } else {
+            result
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoSensorTrait for MockMain {
+    fn get_supported_sensors(&self) -> &SensorInformation {
+        &self.sensors
+    }
+
+    async fn start_sensor(&self, request: &Wifi::SensorStartRequestMessage) -> Result<(), ()> {
+        self.record(MockCall::StartSensor(request.sensor_type()));
+        self.behavior.start_sensor
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoAudioOutputTrait for MockMain {
+    fn supported_output_channels(&self) -> Vec<AudioChannelType> {
+        self.support.output_channels.clone()
+    }
+
+    fn output_audio_formats(&self, t: AudioChannelType) -> Vec<AudioFormat> {
+        let mut formats = vec![t.default_format()];
+        formats.extend_from_slice(&self.support.extra_output_formats);
+        formats
+    }
+
+    async fn output_audio_format(&self, t: AudioChannelType, format: AudioFormat) {
+        self.record(MockCall::OutputAudioFormat(t, format));
+    }
+
+    async fn open_output_channel(&self, t: AudioChannelType) -> Result<(), ChannelOpenError> {
+        self.record(MockCall::OpenOutputChannel(t));
+        self.open_result(self.behavior.open_channel)
+    }
+
+    async fn close_output_channel(&self, t: AudioChannelType) -> Result<(), ()> {
+        self.record(MockCall::CloseOutputChannel(t));
+        Ok(())
+    }
+
+    async fn receive_output_audio(&self, t: AudioChannelType, data: Vec<u8>) {
+        if let Some(m) = self.behavior.panic_on_media {
+            panic!("{}", m);
+        }
+        self.audio_data.lock().unwrap().push((t, data));
+    }
+
+    async fn start_output_audio(&self, t: AudioChannelType) {
+        self.record(MockCall::StartOutputAudio(t));
+    }
+
+    async fn stop_output_audio(&self, t: AudioChannelType) {
+        self.record(MockCall::StopOutputAudio(t));
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoAudioInputTrait for MockMain {
+    async fn open_input_channel(&self) -> Result<(), ChannelOpenError> {
+        self.record(MockCall::OpenInputChannel);
+        self.open_result(self.behavior.open_channel)
+    }
+
+    async fn close_input_channel(&self) -> Result<(), ()> {
+        self.record(MockCall::CloseInputChannel);
+        Ok(())
+    }
+
+    async fn start_input_audio(&self) {
+        self.record(MockCall::StartInputAudio);
+    }
+
+    async fn stop_input_audio(&self) {
+        self.record(MockCall::StopInputAudio);
+    }
+
+    async fn audio_input_ack(&self, chan: u8, _ack: AVMediaAckIndication) {
+        self.record(MockCall::AudioInputAck(chan));
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoInputChannelTrait for MockMain {
+    async fn binding_request(&self, code: u32) -> Result<(), ()> {
+        self.record(MockCall::BindingRequest(code));
+        if self.behavior.failing_bindings.contains(&code) {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn binding_release(&self, code: u32) {
+        self.record(MockCall::BindingRelease(code));
+    }
+
+    fn retrieve_input_configuration(&self) -> &InputConfiguration {
+        &self.input
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoVideoChannelTrait for MockMain {
+    async fn receive_video(&self, data: bytes::Bytes, timestamp: Option<u64>) {
+        if let Some(m) = self.behavior.panic_on_media {
+            panic!("{}", m);
+        }
+        self.video_data.lock().unwrap().push((data, timestamp));
+    }
+
+    async fn setup_video(&self, _config: &VideoConfiguration) -> Result<(), ChannelOpenError> {
+        self.record(MockCall::SetupVideo);
+        self.open_result(self.behavior.setup_video)
+    }
+
+    async fn teardown_video(&self) {
+        self.record(MockCall::TeardownVideo);
+    }
+
+    async fn video_sequence_error(&self, error: FrameSequenceError) {
+        self.record(MockCall::VideoSequenceError(error));
+    }
+
+    async fn video_layout(&self, layout: &VideoLayout) {
+        self.record(MockCall::VideoLayout(layout.clone()));
+    }
+
+    async fn wait_for_focus(&self) {}
+
+    async fn set_focus(&self, focus: bool) {
+        self.record(MockCall::SetFocus(focus));
+    }
+
+    fn retrieve_video_configuration(&self) -> &VideoConfiguration {
+        &self.video
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoMainTrait for MockMain {
+    fn supports_bluetooth(&self) -> Option<&dyn AndroidAutoBluetoothTrait> {
+        self.support.bluetooth.then_some(self as _)
+    }
+
+    fn supports_navigation(&self) -> Option<&dyn AndroidAutoNavigationTrait> {
+        self.support.navigation.then_some(self as _)
+    }
+
+    fn supports_secondary_video(
+        &self,
+    ) -> Option<&(dyn AndroidAutoVideoChannelTrait + Send + Sync)> {
+        self.support.secondary_video.then_some(self as _)
+    }
+
+    fn supports_media_status(&self) -> bool {
+        self.support.media_status
+    }
+
+    #[cfg(feature = "unstable")]
+    fn extra_channels(&self) -> Vec<Wifi::ChannelDescriptor> {
+        self.support.extra_channels.clone()
+    }
+
+    async fn ping_time_microseconds(&self, micros: i64) {
+        self.record(MockCall::PingTime(micros));
+    }
+
+    async fn shutdown_requested(&self, reason: Wifi::shutdown_reason::Enum) {
+        self.record(MockCall::ShutdownRequested(reason));
+    }
+
+    async fn accept_connection(&self) -> Result<(), Option<String>> {
+        self.behavior.accept_connection.clone()
+    }
+
+    async fn connect(&self) {
+        self.record(MockCall::Connect);
+    }
+
+    async fn disconnect(&self) {
+        self.record(MockCall::Disconnect);
+    }
+
+    async fn get_receiver(
+        &self,
+    ) -> Option<tokio::sync::mpsc::Receiver<SendableAndroidAutoMessage>> {
+        self.receiver.lock().unwrap().take()
+    }
+}
+
+#[cfg(feature = "wireless")]
+#[async_trait::async_trait]
+impl AndroidAutoWirelessTrait for MockMain {
+    async fn setup_bluetooth_profile(
+        &self,
+        _suggestions: &bluetooth_rust::BluetoothRfcommProfileSettings,
+    ) -> Result<bluetooth_rust::BluetoothRfcommProfileAsync, String> {
+        Err("The mock head unit has no bluetooth".to_string())
+    }
+
+    fn get_wifi_details(&self) -> NetworkInformation {
+        NetworkInformation {
+            ssid: "Mock".to_string(),
+            psk: "password".to_string(),
+            mac_addr: "00:00:00:00:00:00".to_string(),
+            ip: "10.42.0.1".to_string(),
+            port: 5277,
+            security_mode: Bluetooth::SecurityMode::WPA2_PERSONAL,
+            ap_type: Bluetooth::AccessPointType::STATIC,
+            keepalive: None,
+        }
+    }
+
+    fn last_bluetooth_phone(&self) -> Option<String> {
+        self.support.last_bluetooth_phone.clone()
+    }
+
+    async fn connect_bluetooth(
+        &self,
+        address: &str,
+        _settings: &bluetooth_rust::BluetoothRfcommProfileSettings,
+    ) -> Result<BluetoothStream, String> {
+        self.record(MockCall::ConnectBluetooth(address.to_string()));
+        Err("The phone did not answer".to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoBluetoothTrait for MockMain {
+    async fn do_stuff(&self) {}
+
+    fn get_config(&self) -> &BluetoothInformation {
+        &self.bluetooth
+    }
+}
+
+#[async_trait::async_trait]
+impl AndroidAutoNavigationTrait for MockMain {
+    async fn turn_indication(&self, _m: Wifi::NavigationTurnEvent) {
+        self.record(MockCall::TurnIndication);
+    }
+
+    async fn distance_indication(&self, _m: Wifi::NavigationDistanceEvent) {}
+
+    async fn nagivation_status(&self, m: Wifi::NavigationStatus) {
+        self.record(MockCall::NavigationStatus(m.status()));
+    }
+
+    async fn navigation_started(&self) {
+        self.record(MockCall::NavigationStarted);
+    }
+
+    async fn navigation_stopped(&self) {
+        self.record(MockCall::NavigationStopped);
+    }
+}
@@ -9,7 +9,7 @@ use crate::{
 
 /// A message about binding input buttons on a compatible android auto head unit
 #[derive(Debug)]
-enum NavigationMessage {
+pub(crate) enum NavigationMessage {
     /// A message indicating navigation status
     Status(ChannelId, Wifi::NavigationStatus),
     /// A message that conveys turn information
@@ -32,9 +32,7 @@ impl TryFrom<&AndroidAutoFrame> for NavigationMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
         use protobuf::Enum;
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if let Some(sys) = Wifi::navigation_channel_message::Enum::from_i32(ty as i32) {
             match sys {
                 Wifi::navigation_channel_message::Enum::STATUS => {
@@ -44,7 +42,9 @@ impl TryFrom<&AndroidAutoFrame> for NavigationMessage {
                         Err(e) => Err(format!("Invalid frame: {}", e)),
                     }
                 }
-                Wifi::navigation_channel_message::Enum::NONE => unimplemented!(),
+                Wifi::navigation_channel_message::Enum::NONE => {
+                    Err("Unsupported message NONE".to_string())
+                }
                 Wifi::navigation_channel_message::Enum::TURN_EVENT => {
                     let m = Wifi::NavigationTurnEvent::parse_from_bytes(&value.data[2..]);
                     match m {
@@ -67,24 +67,48 @@ impl TryFrom<&AndroidAutoFrame> for NavigationMessage {
 }
 
 /// The handler for navigation for the android auto protocol
-pub struct NavigationChannelHandler {}
+pub struct NavigationChannelHandler {
+    /// True while a navigation session is in progress
+    active: std::sync::atomic::AtomicBool,
+}
+
+impl NavigationChannelHandler {
+    /// Construct a new self
+    pub fn new() -> Self {
+        Self {
+            active: std::sync::atomic::AtomicBool::new(false),
+        }
+    }
+
+    /// Record whether a navigation session is in progress, returning true when that changed
+    fn set_active(&self, active: bool) -> bool {
+        self.active
+            .swap(active, std::sync::atomic::Ordering::Relaxed)
+            != active
+    }
+}
 
 impl ChannelHandlerTrait for NavigationChannelHandler {
     fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
         &self,
         _config: &AndroidAutoConfiguration,
         chanid: ChannelId,
-        _main: &T,
+        main: &T,
     ) -> Option<Wifi::ChannelDescriptor> {
+        let config = main
+            .supports_navigation()
+            .map(|n| n.navigation_configuration())
+            .unwrap_or_default();
         let mut chan = Wifi::ChannelDescriptor::new();
         let mut navchan = Wifi::NavigationChannel::new();
-        navchan.set_minimum_interval_ms(1000);
-        navchan.set_type(Wifi::navigation_turn_type::Enum::IMAGE);
+        navchan.set_minimum_interval_ms(config.minimum_interval.as_millis() as u32);
+        navchan.set_type(config.turn_type);
+        // The image options are required even when the turns are not sent as images
         let mut io = Wifi::NavigationImageOptions::new();
-        io.set_colour_depth_bits(16);
+        io.set_colour_depth_bits(config.image_colour_depth as i32);
         io.set_dunno(255);
-        io.set_height(256);
-        io.set_width(256);
+        io.set_height(config.image_height as i32);
+        io.set_width(config.image_width as i32);
         navchan.image_options.0.replace(Box::new(io));
         chan.set_channel_id(chanid as u32);
         chan.navigation_channel.0.replace(Box::new(navchan));
@@ -108,38 +132,153 @@ impl ChannelHandlerTrait for NavigationChannelHandler {
             match msg {
                 NavigationMessage::Status(_, status) => {
                     if let Some(n) = main.supports_navigation() {
+                        let active = matches!(
+                            status.status(),
+                            Wifi::navigation_status::Enum::ACTIVE
+                                | Wifi::navigation_status::Enum::REROUTING
+                        );
+                        let changed = self.set_active(active);
+                        if changed && active {
+                            n.navigation_started().await;
+                        }
                         n.nagivation_status(status).await;
+                        if changed && !active {
+                            n.navigation_stopped().await;
+                        }
                     }
                 }
                 NavigationMessage::TurnIndication(_, turn) => {
                     if let Some(n) = main.supports_navigation() {
+                        if self.set_active(true) {
+                            n.navigation_started().await;
+                        }
                         n.turn_indication(turn).await;
                     }
                 }
                 NavigationMessage::DistanceIndication(_, distance) => {
                     if let Some(n) = main.supports_navigation() {
+                        if self.set_active(true) {
+                            n.navigation_started().await;
+                        }
                         n.distance_indication(distance).await;
                     }
                 }
             }
         }
 
-        let msg2: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::Navigation,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        todo!("{:x?}", msg);
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+
+    async fn connection_ended<T: AndroidAutoMainTrait + ?Sized>(&self, main: &T) {
+        if self.set_active(false)
+            && let Some(n) = main.supports_navigation()
+        {
+            n.navigation_stopped().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCall, MockMain, MockSupport};
+    use crate::testing::*;
+
+    /// A mock head unit that supports navigation
+    fn navigating() -> MockMain {
+        MockMain::default().with_support(MockSupport {
+            navigation: true,
+            ..Default::default()
+        })
+    }
+
+    /// A navigation status as the compatible android auto device sends it
+    fn status(s: Wifi::navigation_status::Enum) -> AndroidAutoFrame {
+        let mut m = Wifi::NavigationStatus::new();
+        m.set_status(s);
+        incoming(
+            7,
+            false,
+            Wifi::navigation_channel_message::Enum::STATUS as u16,
+            &m,
+        )
+    }
+
+    #[tokio::test]
+    async fn status_starts_and_stops_navigation() {
+        use Wifi::navigation_status::Enum::*;
+        let main = navigating();
+        let config = MockMain::configuration();
+        let handler = NavigationChannelHandler::new();
+        let (stream, _sent) = crate::WriteHalf::capturing();
+        for s in [ACTIVE, REROUTING, INACTIVE] {
+            handler
+                .receive_data(status(s), &stream, &config, &main)
+                .await
+                .unwrap();
+        }
+        handler.connection_ended(&main).await;
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::NavigationStarted,
+                MockCall::NavigationStatus(ACTIVE),
+                MockCall::NavigationStatus(REROUTING),
+                MockCall::NavigationStatus(INACTIVE),
+                MockCall::NavigationStopped,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn turn_before_status_starts_navigation_until_the_connection_ends() {
+        let main = navigating();
+        let config = MockMain::configuration();
+        let handler = NavigationChannelHandler::new();
+        let (stream, _sent) = crate::WriteHalf::capturing();
+        let mut m = Wifi::NavigationTurnEvent::new();
+        m.set_street_name("Main Street".to_string());
+        m.set_maneuverDirection(Wifi::maneuver_direction::Enum::LEFT);
+        m.set_maneuverType(Wifi::maneuver_type::Enum::TURN);
+        m.set_turnImage(Vec::new());
+        m.set_roundaboutExitNumber(0);
+        m.set_roundaboutExitAngle(0);
+        let turn = incoming(
+            7,
+            false,
+            Wifi::navigation_channel_message::Enum::TURN_EVENT as u16,
+            &m,
+        );
+        handler
+            .receive_data(turn, &stream, &config, &main)
+            .await
+            .unwrap();
+        handler.connection_ended(&main).await;
+        assert_eq!(
+            main.calls(),
+            vec![
+                MockCall::NavigationStarted,
+                MockCall::TurnIndication,
+                MockCall::NavigationStopped,
+            ]
+        );
     }
 }
@@ -0,0 +1,185 @@
+//! A sink that records received media, for debugging and regression testing
+
+use std::{io::Write, sync::mpsc};
+
+use crate::AudioChannelType;
+
+/// A destination for recorded media
+type RecordWriter = Box<dyn Write + Send>;
+
+/// A request for the thread that does the writing of a media recorder
+enum RecorderCommand {
+    /// Record video to the writer
+    SetVideo(RecordWriter),
+    /// Record audio for the channel to the writer
+    SetAudio(AudioChannelType, RecordWriter),
+    /// Write a chunk of video
+    Video(Vec<u8>, Option<u64>),
+    /// Write a chunk of audio for the channel
+    Audio(AudioChannelType, Vec<u8>, Option<u64>),
+    /// Flush all writers
+    Flush,
+}
+
+/// The writers for a media recorder, owned by the thread that does the writing
+#[derive(Default)]
+struct RecorderInner {
+    /// The writer for video data
+    video: Option<RecordWriter>,
+    /// The writers for audio data, per audio channel
+    audio: Vec<(AudioChannelType, RecordWriter)>,
+}
+
+impl RecorderInner {
+    /// Write a single record
+    fn write_record(w: &mut RecordWriter, data: &[u8], timestamp: Option<u64>) {
+        let r = w
+            .write_all(&timestamp.unwrap_or(0).to_be_bytes())
+            .and_then(|_| w.write_all(&(data.len() as u32).to_be_bytes()))
+            .and_then(|_| w.write_all(data));
+        if let Err(e) = r {
+            log::error!("Failed to record media: {}", e);
+        }
+    }
+
+    /// Carry out commands until every clone of the recorder is dropped
+    fn run(mut self, commands: mpsc::Receiver<RecorderCommand>) {
+        for c in commands {
+            match c {
+                RecorderCommand::SetVideo(w) => self.video = Some(w),
+                RecorderCommand::SetAudio(t, w) => {
+                    self.audio.retain(|(c, _)| *c != t);
+                    self.audio.push((t, w));
+                }
+                RecorderCommand::Video(data, timestamp) => {
+                    if let Some(w) = &mut self.video {
+                        Self::write_record(w, &data, timestamp);
+                    }
+                }
+                RecorderCommand::Audio(t, data, timestamp) => {
+                    if let Some((_, w)) = self.audio.iter_mut().find(|(c, _)| *c == t) {
+                        Self::write_record(w, &data, timestamp);
+                    }
+                }
+                RecorderCommand::Flush => {
+                    let video = self.video.iter_mut();
+                    let audio = self.audio.iter_mut().map(|(_, w)| w);
+                    for w in video.chain(audio) {
+                        if let Err(e) = w.flush() {
+                            log::error!("Failed to flush media recording: {}", e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Records the video and audio received from the compatible android auto device.
+/// Each chunk of media is written as a record: the timestamp in microseconds as a big endian u64 (0 when the phone did not send one),
+/// the length of the data as a big endian u32, then the data itself. For video the data is h264, for audio it is pcm.
+/// These files are for inspecting the media, they cannot be fed to `replay`.
+/// The writing happens on a dedicated thread, so a slow writer never blocks the connection; chunks queue up in memory until written.
+#[derive(Clone)]
+pub struct MediaRecorder {
+    /// The queue of the thread that does the writing, shared between clones of the recorder
+    commands: mpsc::Sender<RecorderCommand>,
+}
+
+impl Default for MediaRecorder {
+    fn default() -> Self {
+        let (commands, recv) = mpsc::channel();
+        std::thread::spawn(move || RecorderInner::default().run(recv));
+        Self { commands }
+    }
+}
+
+impl MediaRecorder {
+    /// Construct a recorder that records nothing, add writers with [`Self::with_video`] and [`Self::with_audio`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue a command for the thread that does the writing
+    fn send(&self, c: RecorderCommand) {
+        // the thread only stops once every sender is gone
+        let _ = self.commands.send(c);
+    }
+
+    /// Construct a recorder that writes video.rec, media.rec, speech.rec and system.rec in the specified directory
+    pub fn to_directory(dir: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let file = |name: &str| -> std::io::Result<std::io::BufWriter<std::fs::File>> {
+            Ok(std::io::BufWriter::new(std::fs::File::create(
+                dir.join(name),
+            )?))
+        };
+        Ok(Self::new()
+            .with_video(file("video.rec")?)
+            .with_audio(AudioChannelType::Media, file("media.rec")?)
+            .with_audio(AudioChannelType::Speech, file("speech.rec")?)
+            .with_audio(AudioChannelType::System, file("system.rec")?))
+    }
+
+    /// Record video to the specified writer
+    pub fn with_video(self, w: impl Write + Send + 'static) -> Self {
+        self.send(RecorderCommand::SetVideo(Box::new(w)));
+        self
+    }
+
+    /// Record audio for the specified channel to the specified writer
+    pub fn with_audio(self, t: AudioChannelType, w: impl Write + Send + 'static) -> Self {
+        self.send(RecorderCommand::SetAudio(t, Box::new(w)));
+        self
+    }
+
+    /// Record a chunk of video
+    pub(crate) fn record_video(&self, data: &[u8], timestamp: Option<u64>) {
+        self.send(RecorderCommand::Video(data.to_vec(), timestamp));
+    }
+
+    /// Record a chunk of audio for the specified channel
+    pub(crate) fn record_audio(&self, t: AudioChannelType, data: &[u8], timestamp: Option<u64>) {
+        self.send(RecorderCommand::Audio(t, data.to_vec(), timestamp));
+    }
+
+    /// Flush all writers once everything recorded so far is written, called when the compatible android auto device disconnects
+    pub fn flush(&self) {
+        self.send(RecorderCommand::Flush);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A writer that hands over everything it has written when flushed
+    struct Flushed(Vec<u8>, mpsc::Sender<Vec<u8>>);
+
+    impl Write for Flushed {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            let _ = self.1.send(std::mem::take(&mut self.0));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn records_are_written_by_the_recorder_thread() {
+        let (send, recv) = mpsc::channel();
+        let recorder =
+            MediaRecorder::new().with_audio(AudioChannelType::Media, Flushed(Vec::new(), send));
+        recorder.record_audio(AudioChannelType::Media, &[7, 8], Some(5));
+        recorder.record_audio(AudioChannelType::Speech, &[9], None);
+        recorder.flush();
+        let written = recv
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(written, [0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 2, 7, 8]);
+    }
+}
@@ -0,0 +1,61 @@
+//! Replay of captured sessions through the channel handlers, for reproducing bugs without a compatible android auto device
+
+use super::*;
+
+/// A single decrypted frame, as received from a compatible android auto device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedFrame {
+    /// The channel the frame was received on
+    pub channel: u8,
+    /// The value of the control bit in the frame header
+    pub control: bool,
+    /// The contents of the frame, starting with the two byte message type
+    pub data: Vec<u8>,
+}
+
+impl From<RecordedFrame> for AndroidAutoFrame {
+    fn from(value: RecordedFrame) -> Self {
+        AndroidAutoFrame {
+            header: FrameHeader {
+                channel_id: value.channel,
+                frame: FrameHeaderContents::new(false, FrameHeaderType::Single, value.control),
+            },
+            data: value.data,
+        }
+    }
+}
+
+/// Feed a captured session through the channel handlers, calling the trait methods of `main` as a live connection would.
+/// Everything the channel handlers would send to the compatible android auto device is discarded.
+/// Frames that fail validation or name a channel that does not exist are logged and skipped.
+/// The files written by [`MediaRecorder`] hold only the received media, not frames, so they cannot be replayed;
+/// the frames for a replay are the decrypted payloads, such as those seen by `unsafe_payload_tap`, with their channel and control bit.
+pub async fn replay<T: AndroidAutoMainTrait + ?Sized>(
+    frames: impl futures::Stream<Item = RecordedFrame>,
+    config: AndroidAutoConfiguration,
+    main: &T,
+) -> Result<(), ClientError> {
+    let channel_handlers = build_channel_handlers(&config, main)?;
+    let stream = WriteHalf::discarding();
+    let mut frames = std::pin::pin!(frames);
+    while let Some(f) = frames.next().await {
+        let f: AndroidAutoFrame = f.into();
+        if let Err(e) = f.validate_control_bit() {
+            log::warn!("Rejecting replayed frame: {}", e);
+            continue;
+        }
+        if let Err(e) = f.check_channel_open(stream.state()) {
+            log::warn!("Rejecting replayed frame: {:?}", e);
+            continue;
+        }
+        if let Some(handler) = channel_handlers.get(f.header.channel_id as usize) {
+            handler.receive_data(f, &stream, &config, main).await?;
+        } else {
+            log::warn!("Replayed frame for unknown channel {}", f.header.channel_id);
+        }
+    }
+    if let Some(r) = &config.recorder {
+        r.flush();
+    }
+    Ok(())
+}
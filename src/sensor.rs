@@ -2,10 +2,12 @@
 
 use super::{
     AndroidAutoCommonMessage, AndroidAutoConfiguration, AndroidAutoFrame, ChannelDescriptor,
-    ChannelHandlerTrait, ChannelId, FrameHeader, FrameHeaderContents, FrameHeaderType,
+    ChannelHandlerTrait, ChannelId, FrameHeader, FrameHeaderContents,
 };
-use crate::{AndroidAutoMainTrait, StreamMux, Wifi};
+use crate::{AndroidAutoMainTrait, ConnectionState, DrivingRestrictions, StreamMux, Wifi};
 use protobuf::Message;
+use std::{collections::HashMap, time::Duration};
+use tokio::time::Instant;
 
 /// A message about sensors in android auto
 #[derive(Debug)]
@@ -33,7 +35,7 @@ impl From<SensorMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -49,7 +51,7 @@ impl From<SensorMessage> for AndroidAutoFrame {
                 AndroidAutoFrame {
                     header: FrameHeader {
                         channel_id: chan,
-                        frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+                        frame: FrameHeaderContents::encrypted_single(),
                     },
                     data: m,
                 }
@@ -62,9 +64,7 @@ impl TryFrom<&AndroidAutoFrame> for SensorMessage {
     type Error = String;
     fn try_from(value: &AndroidAutoFrame) -> Result<Self, Self::Error> {
         use protobuf::Enum;
-        let mut ty = [0u8; 2];
-        ty.copy_from_slice(&value.data[0..2]);
-        let ty = u16::from_be_bytes(ty);
+        let ty = value.message_type()?;
         if let Some(sys) = Wifi::sensor_channel_message::Enum::from_i32(ty as i32) {
             match sys {
                 Wifi::sensor_channel_message::Enum::SENSOR_START_REQUEST => {
@@ -74,9 +74,15 @@ impl TryFrom<&AndroidAutoFrame> for SensorMessage {
                         Err(e) => Err(e.to_string()),
                     }
                 }
-                Wifi::sensor_channel_message::Enum::SENSOR_START_RESPONSE => unimplemented!(),
-                Wifi::sensor_channel_message::Enum::SENSOR_EVENT_INDICATION => unimplemented!(),
-                Wifi::sensor_channel_message::Enum::NONE => unimplemented!(),
+                Wifi::sensor_channel_message::Enum::SENSOR_START_RESPONSE => {
+                    Err("Unsupported message SENSOR_START_RESPONSE".to_string())
+                }
+                Wifi::sensor_channel_message::Enum::SENSOR_EVENT_INDICATION => {
+                    Err("Unsupported message SENSOR_EVENT_INDICATION".to_string())
+                }
+                Wifi::sensor_channel_message::Enum::NONE => {
+                    Err("Unsupported message NONE".to_string())
+                }
             }
         } else {
             Err(format!("Not converted message: {:x?}", value.data))
@@ -84,8 +90,188 @@ impl TryFrom<&AndroidAutoFrame> for SensorMessage {
     }
 }
 
+/// The sensor types of the data carried by a sensor event
+pub(crate) fn event_sensor_types(m: &Wifi::SensorEventIndication) -> Vec<Wifi::sensor_type::Enum> {
+    use Wifi::sensor_type::Enum;
+    let present = [
+        (!m.gps_location.is_empty(), Enum::LOCATION),
+        (!m.compass.is_empty(), Enum::COMPASS),
+        (!m.speed.is_empty(), Enum::CAR_SPEED),
+        (!m.rpm.is_empty(), Enum::RPM),
+        (!m.odometer.is_empty(), Enum::ODOMETER),
+        (!m.fuel_level.is_empty(), Enum::FUEL_LEVEL),
+        (!m.parking_brake.is_empty(), Enum::PARKING_BRAKE),
+        (!m.gear.is_empty(), Enum::GEAR),
+        (!m.diagnostics.is_empty(), Enum::DIAGNOSTICS),
+        (!m.night_mode.is_empty(), Enum::NIGHT_DATA),
+        (!m.environment.is_empty(), Enum::ENVIRONMENT),
+        (!m.hvac.is_empty(), Enum::HVAC),
+        (!m.driving_status.is_empty(), Enum::DRIVING_STATUS),
+        (!m.steering_wheel.is_empty(), Enum::DEAD_RECONING),
+        (!m.passenger.is_empty(), Enum::PASSENGER),
+        (!m.door.is_empty(), Enum::DOOR),
+        (!m.light.is_empty(), Enum::LIGHT),
+        (!m.accel.is_empty(), Enum::ACCEL),
+        (!m.gyro.is_empty(), Enum::GYRO),
+    ];
+    present
+        .into_iter()
+        .filter(|(p, _)| *p)
+        .map(|(_, t)| t)
+        .collect()
+}
+
+/// A sensor event with only the data for the specified sensor type from another sensor event
+fn event_part(
+    m: &Wifi::SensorEventIndication,
+    t: Wifi::sensor_type::Enum,
+) -> Wifi::SensorEventIndication {
+    use Wifi::sensor_type::Enum;
+    let mut p = Wifi::SensorEventIndication::new();
+    match t {
+        Enum::LOCATION => p.gps_location = m.gps_location.clone(),
+        Enum::COMPASS => p.compass = m.compass.clone(),
+        Enum::CAR_SPEED => p.speed = m.speed.clone(),
+        Enum::RPM => p.rpm = m.rpm.clone(),
+        Enum::ODOMETER => p.odometer = m.odometer.clone(),
+        Enum::FUEL_LEVEL => p.fuel_level = m.fuel_level.clone(),
+        Enum::PARKING_BRAKE => p.parking_brake = m.parking_brake.clone(),
+        Enum::GEAR => p.gear = m.gear.clone(),
+        Enum::DIAGNOSTICS => p.diagnostics = m.diagnostics.clone(),
+        Enum::NIGHT_DATA => p.night_mode = m.night_mode.clone(),
+        Enum::ENVIRONMENT => p.environment = m.environment.clone(),
+        Enum::HVAC => p.hvac = m.hvac.clone(),
+        Enum::DRIVING_STATUS => p.driving_status = m.driving_status.clone(),
+        Enum::DEAD_RECONING => p.steering_wheel = m.steering_wheel.clone(),
+        Enum::PASSENGER => p.passenger = m.passenger.clone(),
+        Enum::DOOR => p.door = m.door.clone(),
+        Enum::LIGHT => p.light = m.light.clone(),
+        Enum::ACCEL => p.accel = m.accel.clone(),
+        Enum::GYRO => p.gyro = m.gyro.clone(),
+        Enum::NONE | Enum::GPS | Enum::TIRE => {}
+    }
+    p
+}
+
 /// The handler for the sensor channel in the android auto protocol.
-pub struct SensorChannelHandler {}
+pub struct SensorChannelHandler {
+    /// The minimum interval between events for each sensor, as configured by the user
+    min_intervals: std::sync::Mutex<HashMap<Wifi::sensor_type::Enum, Duration>>,
+    /// The refresh interval requested by the compatible android auto device for each started sensor
+    requested: std::sync::Mutex<HashMap<Wifi::sensor_type::Enum, Duration>>,
+    /// When an event was last sent for each sensor
+    last_sent: std::sync::Mutex<HashMap<Wifi::sensor_type::Enum, Instant>>,
+    /// The latest data for each sensor held back because its sensor sent an event too recently
+    held: std::sync::Mutex<HashMap<Wifi::sensor_type::Enum, Wifi::SensorEventIndication>>,
+}
+
+impl SensorChannelHandler {
+    /// Construct a new self
+    pub fn new() -> Self {
+        Self {
+            min_intervals: std::sync::Mutex::new(HashMap::new()),
+            requested: std::sync::Mutex::new(HashMap::new()),
+            last_sent: std::sync::Mutex::new(HashMap::new()),
+            held: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The minimum interval between events for a sensor, the larger of the one configured by the user and the one requested by the phone
+    fn interval(&self, t: Wifi::sensor_type::Enum) -> Duration {
+        let configured = self.min_intervals.lock().unwrap().get(&t).copied();
+        let requested = self.requested.lock().unwrap().get(&t).copied();
+        configured.max(requested).unwrap_or_default()
+    }
+
+    /// Check a sensor event being sent to the compatible android auto device against the minimum interval of its sensors.
+    /// Returns false when every sensor it carries data for sent an event too recently. The event is then held back,
+    /// replacing any data held for the same sensors, until [`Self::take_due`] gives it out once the interval has elapsed.
+    pub fn check_outgoing(&self, data: &[u8], state: &ConnectionState) -> Result<bool, String> {
+        if data.len() < 2
+            || u16::from_be_bytes([data[0], data[1]])
+                != Wifi::sensor_channel_message::Enum::SENSOR_EVENT_INDICATION as u16
+        {
+            return Ok(true);
+        }
+        let m = Wifi::SensorEventIndication::parse_from_bytes(&data[2..])
+            .map_err(|e| format!("Invalid sensor event: {}", e))?;
+        let types = event_sensor_types(&m);
+        if let Some(s) = m.driving_status.last() {
+            state.set_driving_restrictions(DrivingRestrictions::from_bits(s.status()));
+        }
+        if let Some(n) = m.night_mode.last() {
+            state.set_night_mode(n.is_night());
+        }
+        let now = Instant::now();
+        let mut last_sent = self.last_sent.lock().unwrap();
+        let due: Vec<_> = types
+            .iter()
+            .filter(|t| {
+                last_sent
+                    .get(t)
+                    .is_none_or(|l| now.duration_since(*l) >= self.interval(**t))
+            })
+            .copied()
+            .collect();
+        let mut held = self.held.lock().unwrap();
+        if !types.is_empty() && due.is_empty() {
+            for t in types {
+                held.insert(t, event_part(&m, t));
+            }
+            return Ok(false);
+        }
+        for t in &types {
+            held.remove(t);
+        }
+        for t in due {
+            last_sent.insert(t, now);
+        }
+        Ok(true)
+    }
+
+    /// When the first of the held back sensor events is due to be sent, None when nothing is held
+    pub fn next_flush(&self) -> Option<Instant> {
+        let last_sent = self.last_sent.lock().unwrap();
+        self.held
+            .lock()
+            .unwrap()
+            .keys()
+            .map(|t| {
+                last_sent
+                    .get(t)
+                    .map(|l| *l + self.interval(*t))
+                    .unwrap_or_else(Instant::now)
+            })
+            .min()
+    }
+
+    /// Take the held back data of every sensor whose minimum interval has elapsed, merged into a single sensor event
+    pub fn take_due(&self) -> Option<Wifi::SensorEventIndication> {
+        let now = Instant::now();
+        let last_sent = self.last_sent.lock().unwrap();
+        let mut held = self.held.lock().unwrap();
+        let due: Vec<_> = held
+            .keys()
+            .filter(|t| {
+                last_sent
+                    .get(t)
+                    .is_none_or(|l| now.duration_since(*l) >= self.interval(**t))
+            })
+            .copied()
+            .collect();
+        let mut merged: Option<Wifi::SensorEventIndication> = None;
+        for t in due {
+            if let Some(p) = held.remove(&t) {
+                let bytes = p.write_to_bytes().unwrap();
+                merged
+                    .get_or_insert_default()
+                    .merge_from_bytes(&bytes)
+                    .unwrap();
+            }
+        }
+        merged
+    }
+}
 
 impl ChannelHandlerTrait for SensorChannelHandler {
     fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
@@ -97,6 +283,13 @@ impl ChannelHandlerTrait for SensorChannelHandler {
         let mut chan = ChannelDescriptor::new();
         let mut sensor = Wifi::SensorChannel::new();
         let s = main.get_supported_sensors();
+        for t in s.unsendable() {
+            log::warn!(
+                "Advertising sensor {:?}, which no sensor event can carry data for",
+                t
+            );
+        }
+        *self.min_intervals.lock().unwrap() = s.min_intervals.clone();
         for s in &s.sensors {
             sensor.sensors.push({
                 let mut sensor1 = Wifi::Sensor::new();
@@ -120,42 +313,259 @@ impl ChannelHandlerTrait for SensorChannelHandler {
         main: &T,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<SensorMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<SensorMessage>(&mut errors) {
             match msg2 {
-                SensorMessage::Event(_chan, _m) => unimplemented!(),
-                SensorMessage::SensorStartResponse(_, _) => unimplemented!(),
+                SensorMessage::Event(_chan, _m) => msg.log_unhandled(),
+                SensorMessage::SensorStartResponse(_, _) => msg.log_unhandled(),
                 SensorMessage::SensorStartRequest(_chan, m) => {
                     let mut m2 = Wifi::SensorStartResponseMessage::new();
 
-                    let stat = match main.start_sensor(m.sensor_type()).await {
-                        Ok(_) => Wifi::status::Enum::OK,
-                        Err(_) => Wifi::status::Enum::FAIL,
+                    let stat = if !main
+                        .get_supported_sensors()
+                        .sensors
+                        .contains(&m.sensor_type())
+                    {
+                        log::warn!(
+                            "Start requested for unsupported sensor {:?}",
+                            m.sensor_type()
+                        );
+                        Wifi::status::Enum::FAIL
+                    } else {
+                        match main.start_sensor(&m).await {
+                            Ok(_) => {
+                                stream.state().update_phone_info(|p| {
+                                    if !p.sensors.contains(&m.sensor_type()) {
+                                        p.sensors.push(m.sensor_type());
+                                    }
+                                });
+                                self.requested.lock().unwrap().insert(
+                                    m.sensor_type(),
+                                    crate::SensorInformation::requested_interval(&m),
+                                );
+                                Wifi::status::Enum::OK
+                            }
+                            Err(_) => Wifi::status::Enum::FAIL,
+                        }
                     };
                     m2.set_status(stat);
                     stream
                         .write_frame(SensorMessage::SensorStartResponse(channel, m2).into())
                         .await?;
+                    if let (Wifi::status::Enum::OK, Wifi::sensor_type::Enum::NIGHT_DATA, Some(n)) =
+                        (stat, m.sensor_type(), stream.state().night_mode())
+                        && let crate::AndroidAutoMessage::Sensor(e) =
+                            crate::AndroidAutoMessage::night_mode(n)
+                    {
+                        stream
+                            .write_frame(SensorMessage::Event(channel, e).into())
+                            .await?;
+                    }
                 }
             }
             return Ok(());
         }
-        let msg2: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
-                AndroidAutoCommonMessage::ChannelOpenRequest(_m) => {
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(Wifi::status::Enum::OK);
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
+                    super::respond_channel_open(
+                        crate::ChannelKind::Sensor,
+                        channel,
+                        &m,
+                        async { true },
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        todo!("{:x?}", msg);
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::AndroidAutoMessage;
+
+    /// A sensor event with the speed
+    fn speed(speed: i32) -> AndroidAutoMessage {
+        let mut s = Wifi::Speed::new();
+        s.set_speed(speed);
+        let mut m = Wifi::SensorEventIndication::new();
+        m.speed.push(s);
+        AndroidAutoMessage::Sensor(m)
+    }
+
+    /// Check a message the user sends
+    fn check(h: &SensorChannelHandler, m: AndroidAutoMessage, state: &ConnectionState) -> bool {
+        h.check_outgoing(&m.sendable().data, state).unwrap()
+    }
+
+    /// A sensor handler where the phone requested the specified interval for the sensors
+    fn handler(sensors: &[Wifi::sensor_type::Enum], interval: Duration) -> SensorChannelHandler {
+        let h = SensorChannelHandler::new();
+        for s in sensors {
+            h.requested.lock().unwrap().insert(*s, interval);
+        }
+        h
+    }
+
+    /// The night modes of the sensor events written for the user
+    fn night_modes(messages: Vec<crate::SendableAndroidAutoMessage>) -> Vec<bool> {
+        messages
+            .iter()
+            .map(|m| {
+                Wifi::SensorEventIndication::parse_from_bytes(&m.data[2..])
+                    .unwrap()
+                    .night_mode[0]
+                    .is_night()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn night_mode_is_only_sent_when_it_changes() {
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        stream
+            .state()
+            .update_phone_info(|p| p.sensors.push(Wifi::sensor_type::Enum::NIGHT_DATA));
+        let conn = crate::Connection::new(stream);
+        let (a, b) = tokio::join!(conn.set_night_mode(true), conn.set_night_mode(true));
+        a.unwrap();
+        b.unwrap();
+        conn.set_night_mode(true).await.unwrap();
+        conn.set_night_mode(false).await.unwrap();
+        assert_eq!(night_modes(sent.messages()), vec![true, false]);
+        assert_eq!(conn.night_mode(), Some(false));
+    }
+
+    #[tokio::test]
+    async fn night_mode_is_held_until_the_sensor_starts() {
+        let main = crate::mock::MockMain::default().with_sensors(
+            crate::SensorInformation::new([Wifi::sensor_type::Enum::NIGHT_DATA]).unwrap(),
+        );
+        let config = crate::mock::MockMain::configuration();
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        let conn = crate::Connection::new(stream.clone());
+        conn.set_night_mode(true).await.unwrap();
+        assert!(sent.messages().is_empty());
+        let mut request = Wifi::SensorStartRequestMessage::new();
+        request.set_sensor_type(Wifi::sensor_type::Enum::NIGHT_DATA);
+        request.set_refresh_interval(0);
+        let h = SensorChannelHandler::new();
+        h.receive_data(
+            crate::testing::incoming(
+                2,
+                false,
+                Wifi::sensor_channel_message::Enum::SENSOR_START_REQUEST as u16,
+                &request,
+            ),
+            &stream,
+            &config,
+            &main,
+        )
+        .await
+        .unwrap();
+        let frames = sent.frames();
+        let response: Wifi::SensorStartResponseMessage = crate::testing::response(
+            &frames,
+            Wifi::sensor_channel_message::Enum::SENSOR_START_RESPONSE as u16,
+        );
+        assert_eq!(response.status(), Wifi::status::Enum::OK);
+        let event: Wifi::SensorEventIndication = crate::testing::response(
+            &frames,
+            Wifi::sensor_channel_message::Enum::SENSOR_EVENT_INDICATION as u16,
+        );
+        assert!(event.night_mode[0].is_night());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn held_event_is_sent_once_the_interval_elapses() {
+        let interval = Duration::from_millis(100);
+        let h = handler(&[Wifi::sensor_type::Enum::NIGHT_DATA], interval);
+        let state = ConnectionState::new();
+        let start = Instant::now();
+        assert!(check(&h, AndroidAutoMessage::night_mode(true), &state));
+        assert!(!check(&h, AndroidAutoMessage::night_mode(false), &state));
+        assert_eq!(state.night_mode(), Some(false));
+        assert!(h.take_due().is_none());
+        assert_eq!(h.next_flush(), Some(start + interval));
+        tokio::time::advance(interval).await;
+        let held = h.take_due().unwrap();
+        assert!(!held.night_mode[0].is_night());
+        assert!(check(&h, AndroidAutoMessage::Sensor(held), &state));
+        assert!(h.take_due().is_none());
+        assert_eq!(h.next_flush(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn default_interval_delays_a_state_change() {
+        let h = SensorChannelHandler::new();
+        let mut request = Wifi::SensorStartRequestMessage::new();
+        request.set_sensor_type(Wifi::sensor_type::Enum::DRIVING_STATUS);
+        request.set_refresh_interval(0);
+        h.requested.lock().unwrap().insert(
+            request.sensor_type(),
+            crate::SensorInformation::requested_interval(&request),
+        );
+        let state = ConnectionState::new();
+        assert!(check(
+            &h,
+            AndroidAutoMessage::driving_status(DrivingRestrictions::UNRESTRICTED),
+            &state
+        ));
+        assert!(!check(
+            &h,
+            AndroidAutoMessage::driving_status(DrivingRestrictions::FULLY_RESTRICTED),
+            &state
+        ));
+        tokio::time::advance(crate::SensorInformation::DEFAULT_REFRESH_INTERVAL / 2).await;
+        assert!(h.take_due().is_none());
+        tokio::time::advance(crate::SensorInformation::DEFAULT_REFRESH_INTERVAL / 2).await;
+        let held = h.take_due().unwrap();
+        assert_eq!(
+            DrivingRestrictions::from_bits(held.driving_status[0].status()),
+            DrivingRestrictions::FULLY_RESTRICTED
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn latest_held_data_of_each_sensor_is_sent() {
+        let interval = Duration::from_millis(100);
+        let h = handler(
+            &[
+                Wifi::sensor_type::Enum::CAR_SPEED,
+                Wifi::sensor_type::Enum::DRIVING_STATUS,
+            ],
+            interval,
+        );
+        let state = ConnectionState::new();
+        assert!(check(&h, speed(1), &state));
+        assert!(check(
+            &h,
+            AndroidAutoMessage::driving_status(DrivingRestrictions::UNRESTRICTED),
+            &state
+        ));
+        assert!(!check(
+            &h,
+            AndroidAutoMessage::driving_status(DrivingRestrictions::FULLY_RESTRICTED),
+            &state
+        ));
+        for s in 2..10 {
+            assert!(!check(&h, speed(s), &state));
+        }
+        tokio::time::advance(interval).await;
+        let held = h.take_due().unwrap();
+        assert_eq!(held.speed.len(), 1);
+        assert_eq!(held.speed[0].speed(), 9);
+        assert_eq!(held.driving_status.len(), 1);
+        assert_eq!(
+            DrivingRestrictions::from_bits(held.driving_status[0].status()),
+            DrivingRestrictions::FULLY_RESTRICTED
+        );
     }
 }
@@ -1,10 +1,13 @@
 //! SSL code
 
+use std::sync::Arc;
+
 use tokio::io::{AsyncRead, AsyncWrite};
 
 use crate::{
-    AndroidAutoControlMessage, AndroidAutoFrame, AndroidAutoFrameReceiver, FrameHeaderReceiver,
-    FrameReceiptError, FrameTransmissionError, SendableAndroidAutoMessage,
+    ActivityClass, AndroidAutoControlMessage, AndroidAutoFrame, AndroidAutoFrameReceiver,
+    ConnectionState, CryptoBackend, FrameHeaderReceiver, FrameReceiptError, FrameTransmissionError,
+    SendableAndroidAutoMessage, SendableChannelType,
 };
 
 /// A message sent to the ssl thread
@@ -21,31 +24,145 @@ pub enum SslThreadData {
     DecryptMe(AndroidAutoFrame),
 }
 
+/// The priority lanes of the outbound path, in order from highest to lowest priority.
+/// When messages are waiting in more than one lane, the ssl thread takes the one from the highest priority lane first,
+/// except that every [`FAIRNESS_BOUND`] messages the lowest priority lane with a message waiting goes first.
+/// Within a lane messages are handled strictly in the order they were written.
+#[derive(Clone, Copy, Debug)]
+enum Lane {
+    /// Frames written by the channel handlers (responses, acks and other control traffic), the ssl handshake and frames to decrypt
+    Control,
+    /// Input events from the user
+    Input,
+    /// Sensor events from the user
+    Sensor,
+    /// Audio input and any other message from the user
+    Media,
+}
+
+/// The number of lanes in [`Lane`]
+const LANES: usize = 4;
+
+impl Lane {
+    /// The lane for a message written by the user
+    fn for_message(m: &SendableAndroidAutoMessage) -> Self {
+        match m.channel {
+            SendableChannelType::Input => Lane::Input,
+            SendableChannelType::Sensor => Lane::Sensor,
+            SendableChannelType::AudioInput | SendableChannelType::Other => Lane::Media,
+            #[cfg(feature = "unstable")]
+            SendableChannelType::Extra(_) => Lane::Media,
+        }
+    }
+}
+
+/// The most messages the ssl thread takes in priority order before it serves the lowest priority lane with a message waiting,
+/// so sustained traffic in the higher lanes cannot hold back the lower lanes indefinitely
+const FAIRNESS_BOUND: usize = 16;
+
+/// The receiving side of the lanes, taking messages in priority order
+struct LaneReceiver {
+    /// The receivers for each lane, indexed by [`Lane`]
+    lanes: [tokio::sync::mpsc::Receiver<SslThreadData>; LANES],
+    /// The lanes whose senders are all gone and that have nothing left to receive
+    closed: [bool; LANES],
+    /// The messages taken in priority order since the lowest waiting lane was last served
+    taken: usize,
+}
+
+impl LaneReceiver {
+    /// Construct a new self
+    fn new(lanes: [tokio::sync::mpsc::Receiver<SslThreadData>; LANES]) -> Self {
+        Self {
+            lanes,
+            closed: [false; LANES],
+            taken: 0,
+        }
+    }
+
+    /// Take the next message, from the highest priority lane with one waiting.
+    /// Every [`FAIRNESS_BOUND`] messages the lowest priority lane with one waiting is served instead.
+    /// When control only is set just the [`Lane::Control`] lane is served, the messages from the user wait in their lanes.
+    /// Returns None once every lane being served is closed.
+    async fn recv(&mut self, control_only: bool) -> Option<SslThreadData> {
+        loop {
+            if self.taken >= FAIRNESS_BOUND && !control_only {
+                self.taken = 0;
+                for (lane, closed) in self.lanes.iter_mut().zip(&mut self.closed).rev() {
+                    match lane.try_recv() {
+                        Ok(m) => return Some(m),
+                        Err(tokio::sync::mpsc::error::TryRecvError::Disconnected) => *closed = true,
+                        Err(tokio::sync::mpsc::error::TryRecvError::Empty) => {}
+                    }
+                }
+            }
+            let closed = self.closed;
+            let [control, input, sensor, media] = &mut self.lanes;
+            let (lane, m) = tokio::select! {
+                biased;
+                m = control.recv(), if !closed[Lane::Control as usize] => (Lane::Control, m),
+                m = input.recv(), if !control_only && !closed[Lane::Input as usize] => (Lane::Input, m),
+                m = sensor.recv(), if !control_only && !closed[Lane::Sensor as usize] => (Lane::Sensor, m),
+                m = media.recv(), if !control_only && !closed[Lane::Media as usize] => (Lane::Media, m),
+                else => return None,
+            };
+            match m {
+                Some(m) => {
+                    self.taken += 1;
+                    return Some(m);
+                }
+                None => self.closed[lane as usize] = true,
+            }
+        }
+    }
+}
+
+/// Build the senders and receivers for all of the lanes, each lane buffers up to 15 messages
+fn lanes() -> (
+    [tokio::sync::mpsc::Sender<SslThreadData>; LANES],
+    [tokio::sync::mpsc::Receiver<SslThreadData>; LANES],
+) {
+    let [c, i, s, m] = std::array::from_fn(|_| tokio::sync::mpsc::channel(15));
+    ([c.0, i.0, s.0, m.0], [c.1, i.1, s.1, m.1])
+}
+
 /// The response from the ssl thread
 pub enum SslThreadResponse {
     /// A decrypted frame received from the read object
     Data(AndroidAutoFrame),
+    /// A frame that was sent on behalf of the user, for the channel handler to update its state
+    Sent(AndroidAutoFrame),
     /// The handshake is complete
     HandshakeComplete,
     /// The ssl thread is exiting with an error
     ExitError(String),
+    /// The read object reached its end, the transport went away
+    Disconnected,
 }
 
 struct SslStreamThread<U: AsyncWrite + Unpin> {
-    stream: rustls::client::ClientConnection,
+    stream: Box<dyn CryptoBackend>,
     hs_started: bool,
     hs_completed: bool,
-    hs: Option<tokio::sync::mpsc::Receiver<SslThreadData>>,
+    hs: Option<[tokio::sync::mpsc::Receiver<SslThreadData>; LANES]>,
     dout: tokio::sync::mpsc::Sender<SslThreadResponse>,
     write: U,
+    /// The state of the connection, for the decrypt timing
+    state: Arc<ConnectionState>,
+    /// The largest payload of a single frame written
+    max_frame_data_size: usize,
+    /// When the first sensor event held back by the minimum interval of its sensors is due
+    sensor_flush: Option<tokio::time::Instant>,
 }
 
 impl<U: AsyncWrite + Unpin> SslStreamThread<U> {
     fn new(
-        rcv: tokio::sync::mpsc::Receiver<SslThreadData>,
+        rcv: [tokio::sync::mpsc::Receiver<SslThreadData>; LANES],
         dout: tokio::sync::mpsc::Sender<SslThreadResponse>,
-        conn: rustls::client::ClientConnection,
+        conn: Box<dyn CryptoBackend>,
         write: U,
+        state: Arc<ConnectionState>,
+        max_frame_data_size: usize,
     ) -> Self {
         Self {
             stream: conn,
@@ -54,15 +171,62 @@ impl<U: AsyncWrite + Unpin> SslStreamThread<U> {
             hs: Some(rcv),
             dout,
             write,
+            state,
+            max_frame_data_size,
+            sensor_flush: None,
         }
     }
 
+    /// Write a frame to the compatible android auto device.
+    /// A payload too large for a single frame goes out as a multi-frame packet, each frame is built and written completely before the next one is built,
+    /// so a large payload never needs a second full size buffer and a slow writer only ever holds one frame.
+    /// The frame is counted as sent once all of it is written.
+    /// Encrypted frames are refused with [`crate::SslError::MissingStream`] until the ssl handshake is complete.
+    async fn write_frame(&mut self, f: &AndroidAutoFrame) -> Result<(), String> {
+        use tokio::io::AsyncWriteExt;
+        let total = f.data.len();
+        for part in AndroidAutoFrame::build_multi_frame(f.header, &f.data, self.max_frame_data_size)
+        {
+            let session: Option<&mut dyn CryptoBackend> = if self.hs_completed {
+                Some(self.stream.as_mut())
+            } else {
+                None
+            };
+            let d2: Vec<u8> = part
+                .build_vec(session, total)
+                .await
+                .map_err(|e| format!("{:?}", e))?;
+            self.write.write_all(&d2).await.map_err(|e| {
+                let e = match e.kind() {
+                    std::io::ErrorKind::TimedOut => FrameTransmissionError::Timeout,
+                    std::io::ErrorKind::UnexpectedEof => FrameTransmissionError::Disconnected,
+                    _ => FrameTransmissionError::Unexpected(e),
+                };
+                format!("{:?}", e)
+            })?;
+        }
+        self.write.flush().await.map_err(|e| format!("{:?}", e))?;
+        self.state.frame_sent();
+        Ok(())
+    }
+
     async fn handle_receive(&mut self, m: SslThreadData) -> Result<(), String> {
         match m {
             SslThreadData::DecryptMe(mut data) => {
-                if let Err(e) = data.decrypt(&mut self.stream).await {
-                    log::error!("Error receiving frame: {:?}", e);
-                    return Err(format!("frame error {:?}", e));
+                let bytes = data.data.len();
+                let start = std::time::Instant::now();
+                let result = data.decrypt(self.stream.as_mut()).await;
+                self.state.frame_decrypted(bytes, start.elapsed());
+                match result {
+                    Ok(()) => {}
+                    Err(FrameReceiptError::ImplausibleDecryptLength(_, _)) => {
+                        log::warn!("Dropping frame with implausible decrypted length");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        log::error!("Error receiving frame: {:?}", e);
+                        return Err(format!("frame error {:?}", e));
+                    }
                 }
                 self.dout.send(SslThreadResponse::Data(data)).await;
             }
@@ -70,47 +234,24 @@ impl<U: AsyncWrite + Unpin> SslStreamThread<U> {
                 if self.hs_started {
                     unimplemented!();
                 } else {
-                    let mut buf = Vec::new();
-                    self.stream
-                        .write_tls(&mut buf)
-                        .map_err(|e| format!("write_tls: {e}"))?;
+                    let buf = self
+                        .stream
+                        .start_handshake()
+                        .map_err(|e| format!("{:?}", e))?;
                     {
-                        use tokio::io::AsyncWriteExt;
                         let f: AndroidAutoFrame =
                             AndroidAutoControlMessage::SslHandshake(buf).into();
-                        let d2: Vec<u8> = f
-                            .build_vec(Some(&mut self.stream))
-                            .await
-                            .map_err(|e| format!("{:?}", e))?;
-                        self.write
-                            .write_all(&d2)
-                            .await
-                            .map_err(|e| match e.kind() {
-                                std::io::ErrorKind::TimedOut => "write timed out".to_string(),
-                                std::io::ErrorKind::UnexpectedEof => {
-                                    "write disconnected".to_string()
-                                }
-                                _ => format!("write error: {e}"),
-                            })?;
-                        let _ = self.write.flush().await;
+                        self.write_frame(&f).await?;
                         self.hs_started = true;
                     }
                 }
             }
             SslThreadData::HandshakeData(data) => {
-                let mut dc = std::io::Cursor::new(data);
-                self.stream
-                    .read_tls(&mut dc)
-                    .map_err(|e| format!("read_tls: {e}"))?;
-                let state = self
+                let step = self
                     .stream
-                    .process_new_packets()
+                    .handshake_data(&data)
                     .map_err(|e| format!("{:?}", e))?;
-
-                if state.peer_has_closed() {
-                    return Err("peer closed connection during handshake".to_string());
-                }
-                if !self.stream.is_handshaking() && !self.hs_completed {
+                if step.complete && !self.hs_completed {
                     self.hs_completed = true;
                     self.dout
                         .send(SslThreadResponse::HandshakeComplete)
@@ -118,91 +259,92 @@ impl<U: AsyncWrite + Unpin> SslStreamThread<U> {
                         .map_err(|e| e.to_string())?;
                 }
 
-                if self.stream.wants_write() {
-                    use tokio::io::AsyncWriteExt;
-                    let mut s = Vec::new();
-                    self.stream
-                        .write_tls(&mut s)
-                        .map_err(|e| format!("write_tls: {e}"))?;
-                    {
-                        let f: AndroidAutoFrame = AndroidAutoControlMessage::SslHandshake(s).into();
-                        let d2: Vec<u8> = f
-                            .build_vec(Some(&mut self.stream))
-                            .await
-                            .map_err(|e| format!("{:?}", e))?;
-                        self.write
-                            .write_all(&d2)
-                            .await
-                            .map_err(|e| match e.kind() {
-                                std::io::ErrorKind::TimedOut => "Timed out".to_string(),
-                                std::io::ErrorKind::UnexpectedEof => "Disconnected".to_string(),
-                                _ => format!("write error: {e}"),
-                            })?;
-                        let _ = self.write.flush().await;
-                    }
+                if !step.output.is_empty() {
+                    let f: AndroidAutoFrame =
+                        AndroidAutoControlMessage::SslHandshake(step.output).into();
+                    self.write_frame(&f).await?;
                 }
             }
             SslThreadData::PlainData(f) => {
-                use tokio::io::AsyncWriteExt;
-                let d2: Vec<u8> = f
-                    .into_frame()
-                    .await
-                    .build_vec(Some(&mut self.stream))
-                    .await
-                    .map_err(|e| format!("{:?}", e))?;
-                let a = self.write.write_all(&d2).await.map_err(|e| match e.kind() {
-                    std::io::ErrorKind::TimedOut => FrameTransmissionError::Timeout,
-                    std::io::ErrorKind::UnexpectedEof => FrameTransmissionError::Disconnected,
-                    _ => FrameTransmissionError::Unexpected(e),
-                });
-                let _ = self.write.flush().await;
-                a.map_err(|e| format!("{:?}", e))?;
+                let sensor = matches!(f.channel, SendableChannelType::Sensor);
+                let frame = f.into_frame(&self.state).await;
+                if sensor {
+                    self.sensor_flush = SendableAndroidAutoMessage::next_sensor_flush().await;
+                }
+                let (f, report) = match frame {
+                    Ok(Some(f)) => f,
+                    Ok(None) => {
+                        log::trace!("Holding back a sensor event until its interval has elapsed");
+                        return Ok(());
+                    }
+                    Err(e) => {
+                        log::error!("Not sending message: {}", e);
+                        return Ok(());
+                    }
+                };
+                if f.header.frame.get_encryption() && !self.hs_completed {
+                    log::error!("Not sending message before the ssl handshake is complete");
+                    return Ok(());
+                }
+                self.write_frame(&f).await?;
+                if report {
+                    self.dout
+                        .send(SslThreadResponse::Sent(f))
+                        .await
+                        .map_err(|e| e.to_string())?;
+                }
             }
             SslThreadData::Frame(f) => {
-                use tokio::io::AsyncWriteExt;
-                let d2: Vec<u8> = f
-                    .build_vec(Some(&mut self.stream))
-                    .await
-                    .map_err(|e| format!("{:?}", e))?;
-                let a = self.write.write_all(&d2).await.map_err(|e| match e.kind() {
-                    std::io::ErrorKind::TimedOut => FrameTransmissionError::Timeout,
-                    std::io::ErrorKind::UnexpectedEof => FrameTransmissionError::Disconnected,
-                    _ => FrameTransmissionError::Unexpected(e),
-                });
-                let _ = self.write.flush().await;
-                a.map_err(|e| format!("{:?}", e))?;
+                self.write_frame(&f).await?;
             }
         }
         Ok(())
     }
 
     async fn run(mut self) -> Result<(), String> {
-        let mut hs = self
-            .hs
-            .take()
-            .expect("SslStreamThread::run called without receiver");
+        let mut lanes = LaneReceiver::new(
+            self.hs
+                .take()
+                .expect("SslStreamThread::run called without receiver"),
+        );
         loop {
-            match hs.recv().await {
-                Some(m) => {
-                    if let Err(e) = self.handle_receive(m).await {
-                        let _ = self
-                            .dout
-                            .send(SslThreadResponse::ExitError(e.to_string()))
-                            .await;
-                        return Err(e);
+            let flush = self.sensor_flush;
+            let m = tokio::select! {
+                // the messages from the user are encrypted, so they wait for the ssl handshake to complete
+                m = lanes.recv(!self.hs_completed) => match m {
+                    Some(m) => m,
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(flush.unwrap_or_else(tokio::time::Instant::now)),
+                    if flush.is_some() =>
+                {
+                    match SendableAndroidAutoMessage::held_sensor_events().await {
+                        Some(m) => SslThreadData::PlainData(m),
+                        None => {
+                            self.sensor_flush =
+                                SendableAndroidAutoMessage::next_sensor_flush().await;
+                            continue;
+                        }
                     }
                 }
-                None => {
-                    return Ok(());
-                }
+            };
+            if let Err(e) = self.handle_receive(m).await {
+                let _ = self
+                    .dout
+                    .send(SslThreadResponse::ExitError(e.clone()))
+                    .await;
+                return Err(e);
             }
         }
+        Ok(())
     }
 }
 
 pub struct StreamMux {
-    send: tokio::sync::mpsc::Sender<SslThreadData>,
+    send: [tokio::sync::mpsc::Sender<SslThreadData>; LANES],
     recv: tokio::sync::mpsc::Receiver<SslThreadResponse>,
+    /// The state of the connection, shared with the ssl thread
+    state: Arc<ConnectionState>,
 }
 
 pub struct ReadHalf {
@@ -211,35 +353,131 @@ pub struct ReadHalf {
 
 #[derive(Clone)]
 pub struct WriteHalf {
-    send: tokio::sync::mpsc::Sender<SslThreadData>,
+    /// The senders for each lane of the outbound path, indexed by [`Lane`]
+    send: [tokio::sync::mpsc::Sender<SslThreadData>; LANES],
+    /// The state of the connection this writes to
+    state: Arc<ConnectionState>,
 }
 
 impl WriteHalf {
+    /// Construct a write half that is not connected to a compatible android auto device, everything written to it is discarded
+    #[cfg(feature = "test-util")]
+    pub fn discarding() -> Self {
+        let (send, recv) = lanes();
+        for mut recv in recv {
+            tokio::spawn(async move { while recv.recv().await.is_some() {} });
+        }
+        Self {
+            send,
+            state: Arc::new(ConnectionState::new()),
+        }
+    }
+
+    /// Construct a write half that is not connected to a compatible android auto device, keeping the frames written to it for inspection
+    #[cfg(test)]
+    pub(crate) fn capturing() -> (Self, FrameCapture) {
+        let (send, recv) = lanes();
+        (
+            Self {
+                send,
+                state: Arc::new(ConnectionState::new()),
+            },
+            FrameCapture { recv },
+        )
+    }
+
+    /// The state of the connection this writes to
+    pub(crate) fn state(&self) -> &Arc<ConnectionState> {
+        &self.state
+    }
+
+    /// Returns true while the connection this writes to is still up
+    pub fn is_connected(&self) -> bool {
+        !self.send[Lane::Control as usize].is_closed()
+    }
+
+    /// Pass data to the ssl thread in the specified lane
+    async fn send(
+        &self,
+        lane: Lane,
+        d: SslThreadData,
+    ) -> Result<(), tokio::sync::mpsc::error::SendError<SslThreadData>> {
+        self.send[lane as usize].send(d).await
+    }
+
     pub async fn write_message(
         &self,
         m: SendableAndroidAutoMessage,
     ) -> Result<(), tokio::sync::mpsc::error::SendError<SslThreadData>> {
-        self.send.send(SslThreadData::PlainData(m)).await
+        let class = match m.channel {
+            SendableChannelType::Input => Some(ActivityClass::Input),
+            SendableChannelType::AudioInput => Some(ActivityClass::Audio),
+            _ => None,
+        };
+        self.send(Lane::for_message(&m), SslThreadData::PlainData(m))
+            .await?;
+        if let Some(class) = class {
+            self.state.activity(class);
+        }
+        Ok(())
     }
 
     pub async fn write_frame(
         &self,
         f: AndroidAutoFrame,
     ) -> Result<(), tokio::sync::mpsc::error::SendError<SslThreadData>> {
-        self.send.send(SslThreadData::Frame(f)).await
+        self.send(Lane::Control, SslThreadData::Frame(f)).await
     }
 
     pub async fn start_handshake(
         &self,
     ) -> Result<(), tokio::sync::mpsc::error::SendError<SslThreadData>> {
-        self.send.send(SslThreadData::HandshakeStart).await
+        self.send(Lane::Control, SslThreadData::HandshakeStart)
+            .await
     }
 
     pub async fn do_handshake(
         &self,
         data: Vec<u8>,
     ) -> Result<(), tokio::sync::mpsc::error::SendError<SslThreadData>> {
-        self.send.send(SslThreadData::HandshakeData(data)).await
+        self.send(Lane::Control, SslThreadData::HandshakeData(data))
+            .await
+    }
+}
+
+/// The frames written to a [`WriteHalf::capturing`], for tests
+#[cfg(test)]
+pub(crate) struct FrameCapture {
+    /// The receivers for each lane of the outbound path
+    recv: [tokio::sync::mpsc::Receiver<SslThreadData>; LANES],
+}
+
+#[cfg(test)]
+impl FrameCapture {
+    /// Take the frames written by channel handlers since the last call, in lane priority order
+    pub(crate) fn frames(&mut self) -> Vec<AndroidAutoFrame> {
+        let mut frames = Vec::new();
+        for recv in &mut self.recv {
+            while let Ok(d) = recv.try_recv() {
+                if let SslThreadData::Frame(f) = d {
+                    frames.push(f);
+                }
+            }
+        }
+        frames
+    }
+
+    /// Take the messages written for the user since the last call, in lane priority order
+    pub(crate) fn messages(&mut self) -> Vec<SendableAndroidAutoMessage> {
+        let mut messages = Vec::new();
+        for recv in &mut self.recv {
+            while let Ok(d) = recv.try_recv() {
+                if let SslThreadData::PlainData(m) = d {
+                    messages.push(m);
+                }
+            }
+        }
+        messages
     }
 }
 
@@ -251,38 +489,269 @@ impl ReadHalf {
 
 impl StreamMux {
     pub fn new<T: AsyncRead + Send + Unpin + 'static, U: AsyncWrite + Send + Unpin + 'static>(
-        conn: rustls::client::ClientConnection,
+        conn: Box<dyn CryptoBackend>,
         write: U,
         mut read: T,
+        max_frame_data_size: usize,
     ) -> Self {
-        let chan = tokio::sync::mpsc::channel(15);
+        let chan = lanes();
         let chan2 = tokio::sync::mpsc::channel(15);
         let chanw = chan2.0.clone();
-        let stream = SslStreamThread::new(chan.1, chan2.0, conn, write);
+        let state = Arc::new(ConnectionState::new());
+        let stream = SslStreamThread::new(
+            chan.1,
+            chan2.0,
+            conn,
+            write,
+            state.clone(),
+            max_frame_data_size,
+        );
         tokio::spawn(stream.run());
-        let chan_ssl = chan.0.clone();
+        // The reader does not keep the control lane open, the ssl thread exits once every write half is gone
+        let chan_ssl = chan.0[Lane::Control as usize].downgrade();
         tokio::spawn(async move {
-            let mut fr = AndroidAutoFrameReceiver::new();
+            let mut fr = AndroidAutoFrameReceiver::new(max_frame_data_size);
             loop {
                 let mut fhr = FrameHeaderReceiver::new();
-                if let Ok(Some(fh)) = fhr.read(&mut read).await {
-                    if let Ok(Some(f)) = fr.read(&fh, &mut read).await {
-                        if f.header.frame.get_encryption() {
-                            chan_ssl.send(SslThreadData::DecryptMe(f)).await;
-                        } else {
-                            chanw.send(SslThreadResponse::Data(f)).await;
-                        }
+                let f = match fhr.read(&mut read).await {
+                    Ok(Some(fh)) => fr.read(&fh, &mut read).await,
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                let f = match f {
+                    Ok(Some(f)) => f,
+                    Ok(None) => continue,
+                    Err(FrameReceiptError::Disconnected) => {
+                        log::info!("The android auto device disconnected");
+                        let _ = chanw.send(SslThreadResponse::Disconnected).await;
+                        break;
+                    }
+                    Err(e) => {
+                        // A frame that was only partly read leaves the stream out of sync
+                        let e = crate::error_chain(&e);
+                        log::error!("Failed to read a frame: {}", e);
+                        let _ = chanw.send(SslThreadResponse::ExitError(e)).await;
+                        break;
                     }
+                };
+                let sent = if f.header.frame.get_encryption() {
+                    let Some(chan_ssl) = chan_ssl.upgrade() else {
+                        break;
+                    };
+                    chan_ssl.send(SslThreadData::DecryptMe(f)).await.is_ok()
+                } else {
+                    chanw.send(SslThreadResponse::Data(f)).await.is_ok()
+                };
+                if !sent {
+                    break;
                 }
             }
         });
         Self {
             send: chan.0,
             recv: chan2.1,
+            state,
         }
     }
 
     pub fn split(self) -> (ReadHalf, WriteHalf) {
-        (ReadHalf { recv: self.recv }, WriteHalf { send: self.send })
+        (
+            ReadHalf { recv: self.recv },
+            WriteHalf {
+                send: self.send,
+                state: self.state,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AndroidAutoConfiguration, FrameHeader, FrameHeaderContents, FrameHeaderType};
+
+    /// A writer that fails every write, like a transport that went away
+    struct BrokenWriter;
+
+    impl AsyncWrite for BrokenWriter {
+        fn poll_write(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            std::task::Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// A writer that accepts only a few bytes per call and is not ready on every other call, like a slow transport
+    #[derive(Default)]
+    struct ThrottledWriter {
+        /// Everything written so far
+        written: Vec<u8>,
+        /// True when the next call is not ready
+        stall: bool,
+    }
+
+    impl AsyncWrite for ThrottledWriter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<std::io::Result<usize>> {
+            self.stall = !self.stall;
+            if self.stall {
+                cx.waker().wake_by_ref();
+                return std::task::Poll::Pending;
+            }
+            let n = buf.len().min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            std::task::Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    /// An ssl thread that writes to the specified writer, before the ssl handshake
+    fn ssl_thread<U: AsyncWrite + Unpin>(write: U) -> SslStreamThread<U> {
+        crate::tests::install_provider();
+        let backend = crate::rustls_backend(&crate::tests::test_configuration()).unwrap();
+        let (_, recv) = lanes();
+        let (dout, _) = tokio::sync::mpsc::channel(1);
+        SslStreamThread::new(
+            recv,
+            dout,
+            backend,
+            write,
+            Arc::new(ConnectionState::new()),
+            AndroidAutoConfiguration::DEFAULT_MAX_FRAME_DATA_SIZE,
+        )
+    }
+
+    /// An unencrypted frame on the control channel with the specified payload
+    fn plain_frame(data: Vec<u8>) -> AndroidAutoFrame {
+        AndroidAutoFrame {
+            header: FrameHeader {
+                channel_id: 0,
+                frame: FrameHeaderContents::new(false, FrameHeaderType::Single, false),
+            },
+            data,
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_are_counted_when_written() {
+        let mut thread = ssl_thread(Vec::new());
+        thread
+            .write_frame(&plain_frame(vec![0, 1, 2]))
+            .await
+            .unwrap();
+        assert_eq!(thread.state.frames_sent(), 1);
+        let mut thread = ssl_thread(BrokenWriter);
+        assert!(
+            thread
+                .write_frame(&plain_frame(vec![0, 1, 2]))
+                .await
+                .is_err()
+        );
+        assert_eq!(thread.state.frames_sent(), 0);
+    }
+
+    #[tokio::test]
+    async fn partial_writes_produce_the_same_bytes() {
+        let data: Vec<u8> = (0..AndroidAutoConfiguration::DEFAULT_MAX_FRAME_DATA_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect();
+        let mut whole = ssl_thread(Vec::new());
+        whole.write_frame(&plain_frame(data.clone())).await.unwrap();
+        let mut throttled = ssl_thread(ThrottledWriter::default());
+        throttled.write_frame(&plain_frame(data)).await.unwrap();
+        assert_eq!(throttled.write.written, whole.write);
+        assert_eq!(throttled.state.frames_sent(), 1);
+    }
+
+    /// A marker message for the lane tests
+    fn marker(n: u8) -> SslThreadData {
+        SslThreadData::HandshakeData(vec![n])
+    }
+
+    /// The number of a marker message
+    fn marker_of(m: SslThreadData) -> u8 {
+        match m {
+            SslThreadData::HandshakeData(d) => d[0],
+            _ => panic!("not a marker"),
+        }
+    }
+
+    #[tokio::test]
+    async fn media_lane_is_served_under_sustained_control_traffic() {
+        let (send, recv) = lanes();
+        let mut lanes = LaneReceiver::new(recv);
+        send[Lane::Media as usize].send(marker(1)).await.unwrap();
+        let mut position = None;
+        for i in 0..=FAIRNESS_BOUND {
+            send[Lane::Control as usize].send(marker(0)).await.unwrap();
+            if marker_of(lanes.recv(false).await.unwrap()) == 1 {
+                position = Some(i);
+                break;
+            }
+        }
+        assert_eq!(position, Some(FAIRNESS_BOUND));
+    }
+
+    #[tokio::test]
+    async fn user_lanes_wait_while_only_control_is_served() {
+        let (send, recv) = lanes();
+        let mut lanes = LaneReceiver::new(recv);
+        send[Lane::Input as usize].send(marker(1)).await.unwrap();
+        send[Lane::Control as usize].send(marker(0)).await.unwrap();
+        assert_eq!(marker_of(lanes.recv(true).await.unwrap()), 0);
+        let waiting =
+            tokio::time::timeout(std::time::Duration::from_millis(10), lanes.recv(true)).await;
+        assert!(waiting.is_err());
+        assert_eq!(marker_of(lanes.recv(false).await.unwrap()), 1);
+    }
+
+    #[tokio::test]
+    async fn lanes_are_drained_until_all_close() {
+        let (send, recv) = lanes();
+        let mut lanes = LaneReceiver::new(recv);
+        let [control, input, sensor, media] = send;
+        media.send(marker(4)).await.unwrap();
+        sensor.send(marker(3)).await.unwrap();
+        drop(control);
+        drop(input);
+        drop(sensor);
+        assert_eq!(marker_of(lanes.recv(false).await.unwrap()), 3);
+        assert_eq!(marker_of(lanes.recv(false).await.unwrap()), 4);
+        media.send(marker(5)).await.unwrap();
+        assert_eq!(marker_of(lanes.recv(false).await.unwrap()), 5);
+        drop(media);
+        assert!(lanes.recv(false).await.is_none());
     }
 }
@@ -0,0 +1,146 @@
+//! Adapters that turn the media callbacks into streams, for users that prefer consuming a [`futures::Stream`]
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// The sending half of a video stream, call [`Self::send`] from [`crate::AndroidAutoVideoChannelTrait::receive_video`]
+#[derive(Clone)]
+pub struct VideoFrameSender {
+    /// The sender for the frames
+    send: tokio::sync::mpsc::Sender<(bytes::Bytes, Option<u64>)>,
+}
+
+impl VideoFrameSender {
+    /// Pass a chunk of video and its timestamp to the stream.
+    /// When the stream is full this waits for the consumer to catch up, nothing given to this is dropped.
+    /// While waiting the video channel is busy, so once its queue fills the video the compatible android auto device sends meanwhile is dropped and acknowledged,
+    /// which breaks decoding until the next keyframe.
+    /// Frames sent after the stream is dropped are discarded.
+    pub async fn send(&self, data: bytes::Bytes, timestamp: Option<u64>) {
+        let _ = self.send.send((data, timestamp)).await;
+    }
+}
+
+/// A stream of video chunks and their timestamps, as given to [`crate::AndroidAutoVideoChannelTrait::receive_video`].
+/// The stream ends once every [`VideoFrameSender`] for it is dropped.
+pub struct VideoFrameStream {
+    /// The receiver for the frames
+    recv: tokio::sync::mpsc::Receiver<(bytes::Bytes, Option<u64>)>,
+}
+
+impl futures::Stream for VideoFrameStream {
+    type Item = (bytes::Bytes, Option<u64>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+/// Create a video stream that buffers up to `capacity` chunks of video between the sender and the stream
+pub fn video_stream(capacity: usize) -> (VideoFrameSender, VideoFrameStream) {
+    let (send, recv) = tokio::sync::mpsc::channel(capacity);
+    (VideoFrameSender { send }, VideoFrameStream { recv })
+}
+
+/// An item in an [`AudioStream`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AudioStreamItem {
+    /// The phone started sending audio, see [`crate::AndroidAutoAudioOutputTrait::start_output_audio`]
+    Start,
+    /// A chunk of pcm audio, see [`crate::AndroidAutoAudioOutputTrait::receive_output_audio`]
+    Data(Vec<u8>),
+    /// The phone stopped sending audio, see [`crate::AndroidAutoAudioOutputTrait::stop_output_audio`]
+    Stop,
+}
+
+/// The sending half of the audio streams for a single audio output channel.
+/// Call the methods of this from the matching methods of [`crate::AndroidAutoAudioOutputTrait`].
+/// Each time the channel opens a new [`AudioStream`] is created, which ends when the channel closes.
+/// The channel closes when the connection ends, with a call to [`crate::AndroidAutoAudioOutputTrait::close_output_channel`] to pass on to [`Self::close`].
+pub struct AudioStreamSender {
+    /// The number of items buffered between the sender and each stream
+    capacity: usize,
+    /// The sender for the stream of the currently open channel
+    send: std::sync::Mutex<Option<tokio::sync::mpsc::Sender<AudioStreamItem>>>,
+}
+
+impl AudioStreamSender {
+    /// Construct a sender whose streams buffer up to `capacity` items
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            send: std::sync::Mutex::new(None),
+        }
+    }
+
+    /// The channel was opened, returns the stream of audio for it. Any previous stream ends.
+    pub fn open(&self) -> AudioStream {
+        let (send, recv) = tokio::sync::mpsc::channel(self.capacity);
+        self.send.lock().unwrap().replace(send);
+        AudioStream { recv }
+    }
+
+    /// The channel was closed, this ends the stream
+    pub fn close(&self) {
+        self.send.lock().unwrap().take();
+    }
+
+    /// Pass an item to the stream of the open channel.
+    /// When the stream is full this waits for the consumer to catch up, which delays acknowledging the audio to the phone.
+    /// Items are discarded when the channel is not open or the stream has been dropped.
+    async fn item(&self, item: AudioStreamItem) {
+        let send = self.send.lock().unwrap().clone();
+        if let Some(send) = send {
+            let _ = send.send(item).await;
+        }
+    }
+
+    /// The phone started sending audio
+    pub async fn start(&self) {
+        self.item(AudioStreamItem::Start).await;
+    }
+
+    /// Pass a chunk of audio to the stream
+    pub async fn send(&self, data: Vec<u8>) {
+        self.item(AudioStreamItem::Data(data)).await;
+    }
+
+    /// The phone stopped sending audio
+    pub async fn stop(&self) {
+        self.item(AudioStreamItem::Stop).await;
+    }
+}
+
+/// A stream of audio for a single opening of an audio output channel, created by [`AudioStreamSender::open`]
+pub struct AudioStream {
+    /// The receiver for the items
+    recv: tokio::sync::mpsc::Receiver<AudioStreamItem>,
+}
+
+impl futures::Stream for AudioStream {
+    type Item = AudioStreamItem;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.recv.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    #[tokio::test]
+    async fn audio_stream_ends_when_the_channel_closes() {
+        let sender = AudioStreamSender::new(4);
+        let mut stream = sender.open();
+        sender.start().await;
+        sender.send(vec![1, 2]).await;
+        sender.close();
+        assert_eq!(stream.next().await, Some(AudioStreamItem::Start));
+        assert_eq!(stream.next().await, Some(AudioStreamItem::Data(vec![1, 2])));
+        assert_eq!(stream.next().await, None);
+    }
+}
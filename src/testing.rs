@@ -0,0 +1,101 @@
+//! Helpers shared by the tests, for building the frames a compatible android auto device sends and reading the responses
+
+use protobuf::Message;
+
+use crate::{AndroidAutoFrame, ChannelId, FrameHeader, FrameHeaderContents, FrameHeaderType, Wifi};
+
+/// Build a frame as received from the compatible android auto device, after decryption
+pub(crate) fn incoming(
+    channel: ChannelId,
+    control: bool,
+    ty: u16,
+    m: &impl Message,
+) -> AndroidAutoFrame {
+    let mut data = ty.to_be_bytes().to_vec();
+    data.append(&mut m.write_to_bytes().unwrap());
+    AndroidAutoFrame {
+        header: FrameHeader {
+            channel_id: channel,
+            frame: FrameHeaderContents::new(true, FrameHeaderType::Single, control),
+        },
+        data,
+    }
+}
+
+/// A channel open request for the specified channel
+pub(crate) fn channel_open_request(channel: ChannelId) -> AndroidAutoFrame {
+    let mut m = Wifi::ChannelOpenRequest::new();
+    m.set_priority(0);
+    m.set_channel_id(channel as i32);
+    incoming(
+        channel,
+        true,
+        Wifi::CommonMessage::CHANNEL_OPEN_REQUEST as u16,
+        &m,
+    )
+}
+
+/// An av setup request for the specified channel, choosing the specified configuration
+pub(crate) fn setup_request(channel: ChannelId, index: u32) -> AndroidAutoFrame {
+    let mut m = Wifi::AVChannelSetupRequest::new();
+    m.set_config_index(index);
+    incoming(
+        channel,
+        false,
+        Wifi::avchannel_message::Enum::SETUP_REQUEST as u16,
+        &m,
+    )
+}
+
+/// An av media indication without a timestamp for the specified channel
+pub(crate) fn media_indication(channel: ChannelId, media: &[u8]) -> AndroidAutoFrame {
+    let mut data = (Wifi::avchannel_message::Enum::AV_MEDIA_INDICATION as u16)
+        .to_be_bytes()
+        .to_vec();
+    data.extend_from_slice(media);
+    AndroidAutoFrame {
+        header: FrameHeader {
+            channel_id: channel,
+            frame: FrameHeaderContents::new(true, FrameHeaderType::Single, false),
+        },
+        data,
+    }
+}
+
+/// Find the single frame of the specified message type and parse it
+pub(crate) fn response<M: Message>(frames: &[AndroidAutoFrame], ty: u16) -> M {
+    let found: Vec<_> = frames
+        .iter()
+        .filter(|f| u16::from_be_bytes([f.data[0], f.data[1]]) == ty)
+        .collect();
+    assert_eq!(found.len(), 1, "expected one frame of type 0x{:x}", ty);
+    M::parse_from_bytes(&found[0].data[2..]).unwrap()
+}
+
+/// The status of the single channel open response in the frames
+pub(crate) fn channel_open_status(frames: &[AndroidAutoFrame]) -> Wifi::status::Enum {
+    response::<Wifi::ChannelOpenResponse>(frames, Wifi::CommonMessage::CHANNEL_OPEN_RESPONSE as u16)
+        .status()
+}
+
+/// The status of the single av setup response in the frames
+pub(crate) fn setup_status(frames: &[AndroidAutoFrame]) -> Wifi::avchannel_setup_status::Enum {
+    response::<Wifi::AVChannelSetupResponse>(
+        frames,
+        Wifi::avchannel_message::Enum::SETUP_RESPONSE as u16,
+    )
+    .media_status()
+}
+
+/// A service discovery request, as the compatible android auto device sends it on the control channel
+pub(crate) fn service_discovery_request() -> AndroidAutoFrame {
+    let mut m = Wifi::ServiceDiscoveryRequest::new();
+    m.set_device_name("Phone".to_string());
+    m.set_device_brand("Test".to_string());
+    incoming(
+        0,
+        false,
+        Wifi::ControlMessage::SERVICE_DISCOVERY_REQUEST as u16,
+        &m,
+    )
+}
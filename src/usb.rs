@@ -124,18 +124,20 @@ pub async fn claim_aoa_interface(device: &nusb::Device) -> nusb::Interface {
 }
 
 pub struct AndroidAutoUsb {
+    device: nusb::Device,
     ep_in: nusb::io::EndpointRead<nusb::transfer::Bulk>,
     ep_out: nusb::io::EndpointWrite<nusb::transfer::Bulk>,
 }
 
 impl AndroidAutoUsb {
     /// construct a new interface to the android usb device
-    pub fn new(interface: nusb::Interface) -> Option<Self> {
+    pub fn new(device: nusb::Device, interface: nusb::Interface) -> Option<Self> {
         if let Ok(w) = interface.endpoint::<nusb::transfer::Bulk, nusb::transfer::In>(0x81) {
             let a = w.reader(4096);
             if let Ok(w) = interface.endpoint::<nusb::transfer::Bulk, nusb::transfer::Out>(0x1) {
                 let b = w.writer(4096);
                 return Some(Self {
+                    device,
                     ep_in: a,
                     ep_out: b,
                 });
@@ -153,4 +155,13 @@ impl AndroidAutoUsb {
     ) {
         (self.ep_in, self.ep_out)
     }
+
+    /// close the connection, resetting the device so that the phone leaves accessory mode
+    pub async fn close(self) {
+        drop(self.ep_in);
+        drop(self.ep_out);
+        if let Err(e) = self.device.reset().await {
+            log::error!("Failed to reset the usb device: {}", e);
+        }
+    }
 }
@@ -4,43 +4,348 @@ use super::{
     AndroidAutoCommonMessage, AndroidAutoConfiguration, AndroidAutoFrame, AvChannelMessage,
     ChannelHandlerTrait, ChannelId,
 };
-use crate::{AndroidAutoMainTrait, StreamMux, Wifi};
+use crate::{
+    AndroidAutoMainTrait, AndroidAutoVideoChannelTrait, H264Depacketizer, StreamMux,
+    VideoConfiguration, VideoFrameDrop, VideoLayout, VideoOrientation, VideoPacketization, Wifi,
+};
 use protobuf::Message;
 
+impl Wifi::video_resolution::Enum {
+    /// All of the resolutions that have a size
+    const SIZED: [Self; 7] = [
+        Self::_480p,
+        Self::_720p,
+        Self::_1080p,
+        Self::_1440p,
+        Self::_720p_p,
+        Self::_1080pp,
+        Self::_108s0p_p,
+    ];
+
+    /// The width and height in pixels of the video stream for this resolution, if known.
+    /// These are the sizes of the encoded video, independent of the dpi of the display.
+    /// 480p is 800x480, 720p is 1280x720, 1080p is 1920x1080, 1440p is 2560x1440, the portrait variants swap width and height.
+    pub fn size(&self) -> Option<(u16, u16)> {
+        match self {
+            Self::NONE => None,
+            Self::_480p => Some((800, 480)),
+            Self::_720p => Some((1280, 720)),
+            Self::_1080p => Some((1920, 1080)),
+            Self::_1440p => Some((2560, 1440)),
+            Self::_720p_p => Some((720, 1280)),
+            Self::_1080pp => Some((1080, 1920)),
+            Self::_108s0p_p => Some((1440, 2560)),
+        }
+    }
+
+    /// The orientation of this resolution, if it has a size
+    pub fn orientation(&self) -> Option<VideoOrientation> {
+        self.size().map(|(w, h)| {
+            if h > w {
+                VideoOrientation::Portrait
+            } else {
+                VideoOrientation::Landscape
+            }
+        })
+    }
+
+    /// The variant of this resolution in the given orientation, if there is one
+    pub fn with_orientation(&self, o: VideoOrientation) -> Option<Self> {
+        let (w, h) = self.size()?;
+        if self.orientation() == Some(o) {
+            Some(*self)
+        } else {
+            Self::from_size(h, w)
+        }
+    }
+
+    /// Find the resolution with exactly the given width and height in pixels
+    pub fn from_size(width: u16, height: u16) -> Option<Self> {
+        Self::SIZED
+            .into_iter()
+            .find(|r| r.size() == Some((width, height)))
+    }
+}
+
+impl TryFrom<(u16, u16)> for Wifi::video_resolution::Enum {
+    type Error = ();
+
+    fn try_from(value: (u16, u16)) -> Result<Self, Self::Error> {
+        Self::from_size(value.0, value.1).ok_or(())
+    }
+}
+
+impl std::str::FromStr for Wifi::video_resolution::Enum {
+    type Err = String;
+
+    /// Parse a resolution such as "720p", "720p portrait" or "1280x720"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let t = s.trim().to_ascii_lowercase();
+        let r = if let Some((w, h)) = t.split_once('x') {
+            match (w.trim().parse(), h.trim().parse()) {
+                (Ok(w), Ok(h)) => Self::from_size(w, h),
+                _ => None,
+            }
+        } else {
+            match t.split_whitespace().collect::<Vec<_>>().as_slice() {
+                ["480p"] => Some(Self::_480p),
+                ["720p"] => Some(Self::_720p),
+                ["1080p"] => Some(Self::_1080p),
+                ["1440p"] => Some(Self::_1440p),
+                ["720p", "portrait"] => Some(Self::_720p_p),
+                ["1080p", "portrait"] => Some(Self::_1080pp),
+                ["1440p", "portrait"] => Some(Self::_108s0p_p),
+                _ => None,
+            }
+        };
+        r.ok_or(format!("Unknown video resolution {}", s))
+    }
+}
+
+impl Wifi::video_fps::Enum {
+    /// The number of frames per second, if known
+    pub fn fps(&self) -> Option<u32> {
+        match self {
+            Self::NONE => None,
+            Self::_30 => Some(30),
+            Self::_60 => Some(60),
+        }
+    }
+
+    /// Find the fps setting for the given number of frames per second
+    pub fn from_fps(fps: u32) -> Option<Self> {
+        match fps {
+            30 => Some(Self::_30),
+            60 => Some(Self::_60),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Wifi::video_fps::Enum {
+    type Err = String;
+
+    /// Parse an fps such as "30" or "60fps"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let t = s.trim().to_ascii_lowercase();
+        t.strip_suffix("fps")
+            .unwrap_or(&t)
+            .trim()
+            .parse()
+            .ok()
+            .and_then(Self::from_fps)
+            .ok_or(format!("Unknown video fps {}", s))
+    }
+}
+
+/// The headers of the nal units that start in a chunk of annex b h264 video
+fn nal_headers(data: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    data.windows(4)
+        .filter(|w| w[0] == 0 && w[1] == 0 && w[2] == 1)
+        .map(|w| w[3])
+}
+
+/// Whether a chunk of annex b h264 video can be dropped without breaking the decoding of later chunks.
+/// This is only the case when every slice in it is not used as a reference by other frames.
+/// A chunk that does not begin with a start code continues the nal unit the previous chunk ended in, whose header is continued.
+fn is_droppable(data: &[u8], continued: Option<u8>) -> bool {
+    let first = data
+        .windows(3)
+        .position(|w| w[0] == 0 && w[1] == 0 && w[2] == 1);
+    let continues = first.is_none_or(|p| data[..p].iter().any(|b| *b != 0));
+    if continues && continued.is_none() {
+        return false;
+    }
+    let mut slice = false;
+    let headers = continued
+        .filter(|_| continues)
+        .into_iter()
+        .chain(nal_headers(data));
+    for header in headers {
+        let reference = (header >> 5) & 3 != 0;
+        match header & 0x1f {
+            // a slice of a non-idr picture
+            1 if !reference => slice = true,
+            // supplemental enhancement information and access unit delimiters
+            6 | 9 => {}
+            _ => return false,
+        }
+    }
+    slice
+}
+
+/// The most video chunks held back while waiting for the user to finish setting up video
+const EARLY_FRAME_LIMIT: usize = 8;
+
 /// The inner protected data for a video stream
 struct InnerChannelHandler {
     /// The active session for a video stream
     session: Option<i32>,
+    /// The video configuration as advertised to the compatible android auto device
+    advertised: Option<VideoConfiguration>,
+    /// Video data received before video setup completed, with timestamps
+    early: std::collections::VecDeque<(bytes::Bytes, Option<u64>)>,
+    /// The number of frames dropped since the last delivered frame
+    dropped: u32,
+    /// When the last frame was delivered to the user
+    last_delivery: Option<std::time::Instant>,
+    /// The reassembly of access units, when the video configuration asks for it
+    depacketizer: Option<H264Depacketizer>,
+    /// The timestamp of the last video received in the current session
+    last_timestamp: Option<u64>,
+    /// The header of the nal unit the last chunk of video ended in
+    last_nal: Option<u8>,
 }
 
 impl InnerChannelHandler {
     /// construct a new self
     pub fn new() -> Self {
-        Self { session: None }
+        Self {
+            session: None,
+            advertised: None,
+            early: std::collections::VecDeque::new(),
+            dropped: 0,
+            last_delivery: None,
+            depacketizer: None,
+            last_timestamp: None,
+            last_nal: None,
+        }
+    }
+
+    /// Decide if a chunk of video should be given to the user, according to the frame drop policy
+    fn deliver(&mut self, data: &[u8], policy: &VideoFrameDrop) -> bool {
+        let now = std::time::Instant::now();
+        let due = match policy {
+            VideoFrameDrop::DeliverAll => true,
+            VideoFrameDrop::EveryNth(n) => self.dropped + 1 >= *n,
+            VideoFrameDrop::MaxFps(fps) => self.last_delivery.is_none_or(|t| {
+                now.duration_since(t) >= std::time::Duration::from_secs(1) / (*fps).max(1)
+            }),
+        };
+        let droppable = is_droppable(data, self.last_nal);
+        if let Some(header) = nal_headers(data).last() {
+            self.last_nal = Some(header);
+        }
+        if due || !droppable {
+            self.dropped = 0;
+            self.last_delivery = Some(now);
+            true
+        } else {
+            self.dropped += 1;
+            false
+        }
     }
 }
 
-/// The handler for the video channel on android auto
+/// The handler for a video channel on android auto
 pub struct VideoChannelHandler {
     /// The protected contents of a video stream
     inner: std::sync::Mutex<InnerChannelHandler>,
+    /// The result of setting up video with the user, set by whichever of the channel open request or setup request arrives first
+    opened: tokio::sync::OnceCell<bool>,
+    /// True when this is the secondary video stream, handled by [`AndroidAutoMainTrait::supports_secondary_video`]
+    secondary: bool,
 }
 
 impl VideoChannelHandler {
-    /// construct a new self
+    /// construct a new self for the main video stream
     pub fn new() -> Self {
         Self {
             inner: std::sync::Mutex::new(InnerChannelHandler::new()),
+            opened: tokio::sync::OnceCell::new(),
+            secondary: false,
         }
     }
-}
 
-impl ChannelHandlerTrait for VideoChannelHandler {
-    fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
+    /// construct a new self for the secondary video stream
+    pub fn new_secondary() -> Self {
+        Self {
+            secondary: true,
+            ..Self::new()
+        }
+    }
+
+    /// Setup video with the user, only the first call does so unless it fails transiently. Video data held back while waiting for setup is then given to the user,
+    /// after a transient failure it is kept for the next attempt.
+    async fn open<V: AndroidAutoVideoChannelTrait + Sync + ?Sized>(&self, main: &V) -> bool {
+        let opened = super::open_channel_once(&self.opened, crate::ChannelKind::Video, async {
+            let config = self
+                .inner
+                .lock()
+                .unwrap()
+                .advertised
+                .clone()
+                .unwrap_or_else(|| main.retrieve_video_configuration().clone());
+            main.setup_video(&config).await
+        })
+        .await;
+        if self.opened.get().is_none() {
+            return false;
+        }
+        let early: Vec<_> = self.inner.lock().unwrap().early.drain(..).collect();
+        if opened {
+            for (data, timestamp) in early {
+                main.receive_video(data, timestamp).await;
+            }
+        }
+        opened
+    }
+
+    /// Split a chunk of received video into the chunks for the user, according to the packetization of the video configuration
+    fn packetize(
+        &self,
+        data: bytes::Bytes,
+        timestamp: Option<u64>,
+        packetization: VideoPacketization,
+    ) -> Vec<(bytes::Bytes, Option<u64>)> {
+        let delimiters = match packetization {
+            VideoPacketization::AsReceived => return vec![(data, timestamp)],
+            VideoPacketization::AccessUnits => false,
+            VideoPacketization::DelimitedAccessUnits => true,
+        };
+        self.inner
+            .lock()
+            .unwrap()
+            .depacketizer
+            .get_or_insert_with(|| H264Depacketizer::new(delimiters))
+            .push(&data, timestamp)
+            .into_iter()
+            .map(|(au, t)| (bytes::Bytes::from(au), t))
+            .collect()
+    }
+
+    /// Give a chunk of video to the user according to the frame drop policy, holding it back if video is not setup yet
+    async fn deliver<V: AndroidAutoVideoChannelTrait + Sync + ?Sized>(
+        &self,
+        data: bytes::Bytes,
+        timestamp: Option<u64>,
+        main: &V,
+    ) {
+        let policy = &main.retrieve_video_configuration().frame_drop;
+        if !self.inner.lock().unwrap().deliver(&data, policy) {
+            log::trace!("Dropping video frame");
+            return;
+        }
+        match self.opened.get() {
+            Some(true) => main.receive_video(data, timestamp).await,
+            Some(false) => log::trace!("Dropping video for a video setup that failed"),
+            None => {
+                let mut inner = self.inner.lock().unwrap();
+                if inner.early.len() == EARLY_FRAME_LIMIT {
+                    // the oldest chunks hold the parameter sets and keyframe the rest decode from
+                    log::warn!("Video received before setup, dropping the newest chunk");
+                } else {
+                    inner.early.push_back((data, timestamp));
+                }
+            }
+        }
+    }
+
+    /// Build the channel descriptor for the video stream of the specified user
+    fn build<V: AndroidAutoVideoChannelTrait + Sync + ?Sized>(
         &self,
-        _config: &AndroidAutoConfiguration,
         chanid: ChannelId,
-        main: &T,
+        main: &V,
     ) -> Option<Wifi::ChannelDescriptor> {
         let mut chan = Wifi::ChannelDescriptor::new();
         let mut avchan = Wifi::AVChannel::new();
@@ -52,11 +357,52 @@ impl ChannelHandlerTrait for VideoChannelHandler {
         vconfs.push({
             let mut vc = Wifi::VideoConfig::new();
             let vcs = main.retrieve_video_configuration();
-            vc.set_video_resolution(vcs.resolution);
+            let resolution = match vcs.resolution.with_orientation(vcs.orientation) {
+                Some(r) => r,
+                None => {
+                    log::error!(
+                        "Video resolution {:?} has no {:?} variant, using it as is",
+                        vcs.resolution,
+                        vcs.orientation
+                    );
+                    vcs.resolution
+                }
+            };
+            vc.set_video_resolution(resolution);
             vc.set_video_fps(vcs.fps);
-            vc.set_dpi(vcs.dpi as u32);
-            vc.set_margin_height(0);
-            vc.set_margin_width(0);
+            let dpi = main.retrieve_video_dpi();
+            let range = VideoConfiguration::DPI_RANGE;
+            let dpi = if range.contains(&dpi) {
+                dpi
+            } else {
+                let clamped = dpi.clamp(*range.start(), *range.end());
+                log::error!("Video dpi {} is out of range, using {}", dpi, clamped);
+                clamped
+            };
+            vc.set_dpi(dpi as u32);
+            let (mw, mh) = match resolution.size() {
+                Some((w, h)) if vcs.margin_width < w && vcs.margin_height < h => {
+                    (vcs.margin_width, vcs.margin_height)
+                }
+                _ => {
+                    log::error!(
+                        "Video margins {}x{} do not fit resolution {:?}, using no margins",
+                        vcs.margin_width,
+                        vcs.margin_height,
+                        resolution
+                    );
+                    (0, 0)
+                }
+            };
+            vc.set_margin_height(mh as u32);
+            vc.set_margin_width(mw as u32);
+            self.inner.lock().unwrap().advertised = Some(VideoConfiguration {
+                resolution,
+                dpi,
+                margin_width: mw,
+                margin_height: mh,
+                ..vcs.clone()
+            });
             if !vc.is_initialized() {
                 panic!();
             }
@@ -73,64 +419,137 @@ impl ChannelHandlerTrait for VideoChannelHandler {
         Some(chan)
     }
 
-    async fn receive_data<T: super::AndroidAutoMainTrait + ?Sized>(
+    /// Handle the video from a media indication, acknowledging it to the compatible android auto device
+    async fn media<V: AndroidAutoVideoChannelTrait + Sync + ?Sized>(
+        &self,
+        channel: ChannelId,
+        time: Option<u64>,
+        data: bytes::Bytes,
+        stream: &crate::WriteHalf,
+        config: &AndroidAutoConfiguration,
+        main: &V,
+    ) -> Result<(), super::FrameIoError> {
+        if let (Some(r), false) = (&config.recorder, self.secondary) {
+            r.record_video(&data, time);
+        }
+        if let Some(t) = time {
+            let previous = self.inner.lock().unwrap().last_timestamp.replace(t);
+            if let Some(p) = previous.filter(|p| t < *p) {
+                let e = super::FrameSequenceError::VideoTimestampRegressed(p, t);
+                log::warn!("{}", e);
+                main.video_sequence_error(e).await;
+            }
+        }
+        let packetization = main.retrieve_video_configuration().packetization;
+        for (data, time) in self.packetize(data, time, packetization) {
+            self.deliver(data, time, main).await;
+        }
+        self.ack(channel, stream).await
+    }
+
+    /// Acknowledge a media indication to the compatible android auto device
+    pub(crate) async fn ack(
+        &self,
+        channel: ChannelId,
+        stream: &crate::WriteHalf,
+    ) -> Result<(), super::FrameIoError> {
+        let mut m2 = Wifi::AVMediaAckIndication::new();
+        {
+            let inner = self.inner.lock().unwrap();
+            m2.set_session(
+                inner
+                    .session
+                    .ok_or(super::FrameSequenceError::VideoChannelNotOpen)?,
+            );
+        }
+        m2.set_value(1);
+        stream
+            .write_frame(AvChannelMessage::MediaIndicationAck(channel, m2).into())
+            .await?;
+        Ok(())
+    }
+
+    /// Handle a frame for the video stream of the specified user
+    async fn receive<V: AndroidAutoVideoChannelTrait + Sync + ?Sized>(
         &self,
         msg: AndroidAutoFrame,
         stream: &crate::WriteHalf,
-        _config: &AndroidAutoConfiguration,
-        main: &T,
+        config: &AndroidAutoConfiguration,
+        main: &V,
     ) -> Result<(), super::FrameIoError> {
         let channel = msg.header.channel_id;
-        let msg2: Result<AndroidAutoCommonMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        let msg = match AvChannelMessage::take_media(msg) {
+            Ok((time, data)) => return self.media(channel, time, data, stream, config, main).await,
+            Err(msg) => msg,
+        };
+        let mut errors = Vec::new();
+        if let Some(msg2) = msg.parse::<AndroidAutoCommonMessage>(&mut errors) {
             match msg2 {
-                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => unimplemented!(),
+                AndroidAutoCommonMessage::ChannelOpenResponse(_, _) => msg.log_unhandled(),
                 AndroidAutoCommonMessage::ChannelOpenRequest(m) => {
-                    log::info!("Got channel open request for video: {:?}", m);
-                    let mut m2 = Wifi::ChannelOpenResponse::new();
-                    m2.set_status(if main.setup_video().await.is_ok() {
-                        Wifi::status::Enum::OK
-                    } else {
-                        Wifi::status::Enum::FAIL
-                    });
-                    stream
-                        .write_frame(
-                            AndroidAutoCommonMessage::ChannelOpenResponse(channel, m2).into(),
-                        )
-                        .await?;
+                    super::respond_channel_open(
+                        crate::ChannelKind::Video,
+                        channel,
+                        &m,
+                        self.open(main),
+                        stream,
+                    )
+                    .await?;
                 }
             }
             return Ok(());
         }
-        let msg2: Result<AvChannelMessage, String> = (&msg).try_into();
-        if let Ok(msg2) = msg2 {
+        if let Some(msg2) = msg.parse::<AvChannelMessage>(&mut errors) {
             match msg2 {
-                AvChannelMessage::AvChannelOpen(_chan, _m) => todo!(),
-                AvChannelMessage::MediaIndicationAck(_, _) => unimplemented!(),
+                AvChannelMessage::AvChannelOpen(_chan, _m) => msg.log_unhandled(),
+                AvChannelMessage::AvChannelOpenResponse(_, _) => msg.log_unhandled(),
+                AvChannelMessage::MediaIndicationAck(_, _) => msg.log_unhandled(),
                 AvChannelMessage::MediaIndication(_chan, time, data) => {
-                    main.receive_video(data, time).await;
-                    let mut m2 = Wifi::AVMediaAckIndication::new();
-                    {
-                        let inner = self.inner.lock().unwrap();
-                        m2.set_session(
-                            inner
-                                .session
-                                .ok_or(super::FrameSequenceError::VideoChannelNotOpen)?,
-                        );
-                    }
-                    m2.set_value(1);
-                    stream
-                        .write_frame(AvChannelMessage::MediaIndicationAck(channel, m2).into())
+                    self.media(channel, time, data.into(), stream, config, main)
                         .await?;
                 }
-                AvChannelMessage::SetupRequest(_chan, _m) => {
+                AvChannelMessage::SetupRequest(_chan, m) => {
+                    let advertised = self.inner.lock().unwrap().advertised.clone();
+                    let index = m.config_index();
+                    // only a single video configuration is ever advertised
+                    let valid = advertised.is_some() && index == 0;
+                    if !valid {
+                        log::error!(
+                            "Video setup chose configuration {}, which was not advertised",
+                            index
+                        );
+                    }
+                    if let Some(a) = advertised.filter(|_| valid) {
+                        let layout = VideoLayout {
+                            config_index: index,
+                            resolution: a.resolution,
+                            margin_width: a.margin_width,
+                            margin_height: a.margin_height,
+                            dpi: a.dpi,
+                        };
+                        log::debug!("Video layout {:?}", layout);
+                        main.video_layout(&layout).await;
+                    }
+                    let opened = valid && self.open(main).await;
                     let mut m2 = Wifi::AVChannelSetupResponse::new();
                     m2.set_max_unacked(1);
-                    m2.set_media_status(Wifi::avchannel_setup_status::Enum::OK);
-                    m2.configs.push(0);
+                    m2.set_media_status(if opened {
+                        Wifi::avchannel_setup_status::Enum::OK
+                    } else {
+                        Wifi::avchannel_setup_status::Enum::FAIL
+                    });
+                    if valid {
+                        m2.configs.push(index);
+                    }
                     stream
                         .write_frame(AvChannelMessage::SetupResponse(channel, m2).into())
                         .await?;
+                    if !opened {
+                        return Ok(());
+                    }
+                    stream
+                        .state()
+                        .channel_opened(channel, crate::ChannelKind::Video);
                     main.wait_for_focus().await;
                     let mut m2 = Wifi::VideoFocusIndication::new();
                     m2.set_focus_mode(Wifi::video_focus_mode::Enum::FOCUSED);
@@ -139,7 +558,7 @@ impl ChannelHandlerTrait for VideoChannelHandler {
                         .write_frame(AvChannelMessage::VideoIndicationResponse(channel, m2).into())
                         .await?;
                 }
-                AvChannelMessage::SetupResponse(_chan, _m) => unimplemented!(),
+                AvChannelMessage::SetupResponse(_chan, _m) => msg.log_unhandled(),
                 AvChannelMessage::VideoFocusRequest(_chan, m) => {
                     let mut m2 = Wifi::VideoFocusIndication::new();
                     main.set_focus(m.focus_mode() == Wifi::video_focus_mode::Enum::FOCUSED)
@@ -150,18 +569,401 @@ impl ChannelHandlerTrait for VideoChannelHandler {
                         .write_frame(AvChannelMessage::VideoIndicationResponse(channel, m2).into())
                         .await?;
                 }
-                AvChannelMessage::VideoIndicationResponse(_, _) => unimplemented!(),
+                AvChannelMessage::VideoIndicationResponse(_, _) => msg.log_unhandled(),
                 AvChannelMessage::StartIndication(_chan, m) => {
                     let mut inner = self.inner.lock().unwrap();
                     inner.session = Some(m.session());
+                    inner.last_timestamp = None;
+                    inner.last_nal = None;
                 }
                 AvChannelMessage::StopIndication(_chan, _m) => {
-                    let mut inner = self.inner.lock().unwrap();
-                    inner.session.take();
+                    let last = {
+                        let mut inner = self.inner.lock().unwrap();
+                        inner.session.take();
+                        inner.depacketizer.as_mut().and_then(|d| d.flush())
+                    };
+                    if let Some((data, time)) = last {
+                        self.deliver(data.into(), time, main).await;
+                    }
                 }
             }
             return Ok(());
         }
-        todo!("{:x?}", msg);
+        msg.log_parse_errors(&errors);
+        Ok(())
+    }
+}
+
+impl ChannelHandlerTrait for VideoChannelHandler {
+    fn build_channel<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        _config: &AndroidAutoConfiguration,
+        chanid: ChannelId,
+        main: &T,
+    ) -> Option<Wifi::ChannelDescriptor> {
+        if self.secondary {
+            self.build(chanid, main.supports_secondary_video()?)
+        } else {
+            self.build(chanid, main)
+        }
+    }
+
+    async fn receive_data<T: AndroidAutoMainTrait + ?Sized>(
+        &self,
+        msg: AndroidAutoFrame,
+        stream: &crate::WriteHalf,
+        config: &AndroidAutoConfiguration,
+        main: &T,
+    ) -> Result<(), super::FrameIoError> {
+        if !self.secondary {
+            self.receive(msg, stream, config, main).await
+        } else if let Some(v) = main.supports_secondary_video() {
+            self.receive(msg, stream, config, v).await
+        } else {
+            msg.log_unhandled();
+            Ok(())
+        }
+    }
+}
+
+/// Received video taken through the video channel handler to a [`crate::mock::MockMain`], set up the way a connection sets it up.
+/// This is the entry point of the `video_delivery` benchmark, not part of the api, it may change at any time.
+#[cfg(feature = "test-util")]
+#[doc(hidden)]
+pub struct VideoDeliveryBench {
+    /// The handler the video goes through
+    handler: VideoChannelHandler,
+    /// Where the acknowledgements of the video go
+    stream: crate::WriteHalf,
+    /// The configuration of the connection
+    config: AndroidAutoConfiguration,
+    /// The head unit the video is delivered to
+    main: crate::mock::MockMain,
+}
+
+#[cfg(feature = "test-util")]
+impl VideoDeliveryBench {
+    /// A decrypted frame received on the video channel
+    fn frame(data: Vec<u8>) -> AndroidAutoFrame {
+        AndroidAutoFrame {
+            header: crate::FrameHeader {
+                channel_id: 3,
+                frame: crate::FrameHeaderContents::new(true, crate::FrameHeaderType::Single, false),
+            },
+            data,
+        }
+    }
+
+    /// A decrypted frame with the specified message received on the video channel
+    fn message(ty: Wifi::avchannel_message::Enum, m: &impl Message) -> AndroidAutoFrame {
+        let mut data = (ty as u16).to_be_bytes().to_vec();
+        data.append(&mut m.write_to_bytes().unwrap());
+        Self::frame(data)
+    }
+
+    /// Build the video channel, then set up and start the video
+    pub async fn new() -> Self {
+        let main = crate::mock::MockMain::default();
+        let config = crate::mock::MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.build_channel(&config, 3, &main);
+        let bench = Self {
+            handler,
+            stream: crate::WriteHalf::discarding(),
+            config,
+            main,
+        };
+        let mut setup = Wifi::AVChannelSetupRequest::new();
+        setup.set_config_index(0);
+        let mut start = Wifi::AVChannelStartIndication::new();
+        start.set_session(1);
+        start.set_config(0);
+        for f in [
+            Self::message(Wifi::avchannel_message::Enum::SETUP_REQUEST, &setup),
+            Self::message(Wifi::avchannel_message::Enum::START_INDICATION, &start),
+        ] {
+            bench.receive_frame(f).await;
+        }
+        bench
+    }
+
+    /// Hand a frame to the handler
+    async fn receive_frame(&self, f: AndroidAutoFrame) {
+        self.handler
+            .receive_data(f, &self.stream, &self.config, &self.main)
+            .await
+            .expect("The video channel handler failed");
+    }
+
+    /// Receive the decrypted payload of a media indication, which starts with the two byte message type,
+    /// and return the video delivered to the head unit for it
+    pub async fn receive(&self, payload: Vec<u8>) -> Vec<(bytes::Bytes, Option<u64>)> {
+        self.receive_frame(Self::frame(payload)).await;
+        self.main.take_video_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockCall, MockMain};
+    use crate::testing::*;
+
+    #[tokio::test]
+    async fn setup_before_channel_open_sets_up_video_once() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.build_channel(&config, 3, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(3, 0), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(
+            setup_status(&sent.frames()),
+            Wifi::avchannel_setup_status::Enum::OK
+        );
+        handler
+            .receive_data(channel_open_request(3), &stream, &config, &main)
+            .await
+            .unwrap();
+        assert_eq!(channel_open_status(&sent.frames()), Wifi::status::Enum::OK);
+        assert!(matches!(
+            main.calls().as_slice(),
+            [MockCall::VideoLayout(_), MockCall::SetupVideo]
+        ));
+    }
+
+    #[tokio::test]
+    async fn setup_reports_the_chosen_layout() {
+        let main = MockMain::default();
+        let video = VideoConfiguration {
+            resolution: Wifi::video_resolution::Enum::_720p,
+            margin_width: 80,
+            margin_height: 40,
+            dpi: 160,
+            ..main.retrieve_video_configuration().clone()
+        };
+        let main = MockMain::new(
+            video,
+            crate::InputConfiguration {
+                keycodes: Vec::new(),
+                touchscreen: None,
+            },
+            crate::SensorInformation {
+                sensors: Default::default(),
+                min_intervals: Default::default(),
+            },
+        );
+        let config = MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.build_channel(&config, 3, &main);
+        handler
+            .receive_data(
+                setup_request(3, 0),
+                &crate::WriteHalf::discarding(),
+                &config,
+                &main,
+            )
+            .await
+            .unwrap();
+        let layout = VideoLayout {
+            config_index: 0,
+            resolution: Wifi::video_resolution::Enum::_720p,
+            margin_width: 80,
+            margin_height: 40,
+            dpi: 160,
+        };
+        assert_eq!(layout.content_area(), Some((40, 20, 1200, 680)));
+        assert_eq!(
+            main.calls(),
+            vec![MockCall::VideoLayout(layout), MockCall::SetupVideo]
+        );
+    }
+
+    #[tokio::test]
+    async fn setup_for_an_unadvertised_configuration_fails() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.build_channel(&config, 3, &main);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        handler
+            .receive_data(setup_request(3, 1), &stream, &config, &main)
+            .await
+            .unwrap();
+        let r: Wifi::AVChannelSetupResponse = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::SETUP_RESPONSE as u16,
+        );
+        assert_eq!(r.media_status(), Wifi::avchannel_setup_status::Enum::FAIL);
+        assert!(r.configs.is_empty());
+        assert!(!stream.state().is_channel_open(3));
+        assert!(main.calls().is_empty());
+    }
+
+    #[tokio::test]
+    async fn delivered_video_shares_the_frame_buffer() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.build_channel(&config, 3, &main);
+        let (stream, _sent) = crate::WriteHalf::capturing();
+        let mut start = Wifi::AVChannelStartIndication::new();
+        start.set_session(1);
+        start.set_config(0);
+        let start = incoming(
+            3,
+            false,
+            Wifi::avchannel_message::Enum::START_INDICATION as u16,
+            &start,
+        );
+        let media = media_indication(3, &[0, 0, 0, 1, 0x65]);
+        let buffer = media.data.as_ptr();
+        for f in [setup_request(3, 0), start, media] {
+            handler
+                .receive_data(f, &stream, &config, &main)
+                .await
+                .unwrap();
+        }
+        let video = main.video_data();
+        assert_eq!(video.len(), 1);
+        // the two byte message type is sliced off, the video is not copied
+        assert_eq!(video[0].0.as_ptr(), buffer.wrapping_add(2));
+        assert_eq!(video[0].0, [0, 0, 0, 1, 0x65][..]);
+    }
+
+    #[tokio::test]
+    async fn early_video_keeps_the_first_chunks() {
+        let main = MockMain::default();
+        let handler = VideoChannelHandler::new();
+        for i in 0..EARLY_FRAME_LIMIT as u8 + 2 {
+            handler.deliver(vec![i].into(), None, &main).await;
+        }
+        assert!(handler.open(&main).await);
+        let received: Vec<_> = main.video_data().into_iter().map(|(d, _)| d[0]).collect();
+        assert_eq!(received, (0..EARLY_FRAME_LIMIT as u8).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn only_media_is_dropped_for_a_busy_channel() {
+        let handler = VideoChannelHandler::new();
+        handler.inner.lock().unwrap().session = Some(5);
+        let handler = crate::ChannelHandler::Video(handler);
+        let (stream, mut sent) = crate::WriteHalf::capturing();
+        assert!(
+            handler
+                .drop_media(&media_indication(3, &[0, 0, 0, 1]), &stream)
+                .await
+                .unwrap()
+        );
+        let ack: Wifi::AVMediaAckIndication = response(
+            &sent.frames(),
+            Wifi::avchannel_message::Enum::AV_MEDIA_ACK_INDICATION as u16,
+        );
+        assert_eq!((ack.session(), ack.value()), (5, 1));
+        for f in [setup_request(3, 0), channel_open_request(3)] {
+            assert!(!handler.drop_media(&f, &stream).await.unwrap());
+        }
+        assert!(sent.frames().is_empty());
+    }
+
+    #[tokio::test]
+    async fn early_video_is_kept_after_a_transient_failure() {
+        let main = MockMain::default().with_behavior(crate::mock::MockBehavior {
+            transient_open_failures: 1,
+            ..Default::default()
+        });
+        let handler = VideoChannelHandler::new();
+        handler.deliver(vec![1].into(), None, &main).await;
+        assert!(!handler.open(&main).await);
+        handler.deliver(vec![2].into(), None, &main).await;
+        assert!(main.video_data().is_empty());
+        assert!(handler.open(&main).await);
+        handler.deliver(vec![3].into(), None, &main).await;
+        let received: Vec<_> = main.video_data().into_iter().map(|(d, _)| d[0]).collect();
+        assert_eq!(received, vec![1, 2, 3]);
+        assert_eq!(
+            main.calls(),
+            vec![MockCall::SetupVideo, MockCall::SetupVideo]
+        );
+    }
+
+    #[tokio::test]
+    async fn regressed_timestamps_are_reported() {
+        let main = MockMain::default();
+        let config = MockMain::configuration();
+        let handler = VideoChannelHandler::new();
+        handler.inner.lock().unwrap().session = Some(1);
+        let stream = crate::WriteHalf::discarding();
+        for t in [100, 200, 150, 160] {
+            handler
+                .media(
+                    3,
+                    Some(t),
+                    vec![0, 0, 0, 1, 0x65].into(),
+                    &stream,
+                    &config,
+                    &main,
+                )
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            main.calls(),
+            vec![MockCall::VideoSequenceError(
+                crate::FrameSequenceError::VideoTimestampRegressed(200, 150)
+            )]
+        );
+    }
+
+    #[test]
+    fn video_enums_convert_to_and_from_human_values() {
+        use Wifi::video_resolution::Enum as Resolution;
+        assert_eq!(Resolution::_720p.size(), Some((1280, 720)));
+        assert_eq!(Resolution::try_from((1080, 1920)), Ok(Resolution::_1080pp));
+        assert_eq!(Resolution::try_from((1000, 1000)), Err(()));
+        assert_eq!(
+            Resolution::_1080p.with_orientation(VideoOrientation::Portrait),
+            Some(Resolution::_1080pp)
+        );
+        assert_eq!(
+            Resolution::_480p.with_orientation(VideoOrientation::Portrait),
+            None
+        );
+        assert_eq!("720p".parse(), Ok(Resolution::_720p));
+        assert_eq!(" 1440P Portrait ".parse(), Ok(Resolution::_108s0p_p));
+        assert_eq!("800x480".parse(), Ok(Resolution::_480p));
+        assert!("720".parse::<Resolution>().is_err());
+        assert_eq!(Wifi::video_fps::Enum::_60.fps(), Some(60));
+        assert_eq!("30fps".parse(), Ok(Wifi::video_fps::Enum::_30));
+        assert_eq!("60".parse(), Ok(Wifi::video_fps::Enum::_60));
+        assert!("25fps".parse::<Wifi::video_fps::Enum>().is_err());
+    }
+
+    #[test]
+    fn continued_nal_units_are_dropped_with_their_start() {
+        let mut inner = InnerChannelHandler::new();
+        let policy = VideoFrameDrop::EveryNth(100);
+        // a slice of a picture that is not a reference, then the rest of it
+        assert!(!inner.deliver(&[0, 0, 0, 1, 0x01, 0x88], &policy));
+        assert!(!inner.deliver(&[0x12, 0x34], &policy));
+        // a slice of a reference picture, then the rest of it
+        assert!(inner.deliver(&[0, 0, 0, 1, 0x41, 0x88], &policy));
+        assert!(inner.deliver(&[0x12, 0x34], &policy));
+    }
+
+    #[tokio::test]
+    async fn no_video_after_failed_setup() {
+        let main = MockMain::default().with_behavior(crate::mock::MockBehavior {
+            setup_video: Err(crate::ChannelOpenError::Permanent),
+            ..Default::default()
+        });
+        let handler = VideoChannelHandler::new();
+        handler.deliver(vec![1].into(), None, &main).await;
+        assert!(!handler.open(&main).await);
+        handler.deliver(vec![2].into(), None, &main).await;
+        assert!(main.video_data().is_empty());
     }
 }